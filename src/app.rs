@@ -1,22 +1,77 @@
-use crate::cpu::CPU;
+use crate::cache::Cache;
+use crate::console::Console;
+use crate::cpu::{CPU, OpCode, disassemble};
+use crate::datapath;
+use crate::debugger::{self, Command, Debugger, Position};
+use crate::edit::{self, EditTarget};
+use crate::highlight::Highlights;
+use crate::keymap::{Action, KeyMap};
+use crate::micro::{self, FETCH_SEQUENCE, MicroSequencer};
+use crate::microcode::{ControlUnit, Sequence};
+use crate::pipeline::{Pipeline, STAGE_NAMES};
+use crate::snapshot;
+use crate::trace::{Trace, TraceRecord};
+use crate::watchdog::Watchdog;
 
 use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
 use ratatui::{
     DefaultTerminal, Frame,
     layout::{Constraint, Direction, Layout},
-    style::{Modifier, Style},
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph},
 };
 
 use std::io;
+use std::time::{Duration, Instant};
+
+// Steps per second in continuous mode, changed with +/-
+const SPEEDS: [u32; 12] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000];
+const DEFAULT_SPEED: usize = 4; // 20/s
+
+// Screen refresh interval while running, turbo runs flat out in between
+const FRAME_TIME: Duration = Duration::from_millis(33);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Focus {
+    Memory,
+    Status,
+    RegisterLogs,
+}
 
 pub struct App {
     pub cpu: CPU,
     pub program: Vec<u16>,
+    pub pipeline: Option<Pipeline>, // Pipelined execution when set
+    pub control_unit: Option<ControlUnit>, // Microcoded execution when set
     pub memory_list_state: ListState,
-    pub register_logs: Vec<String>,
-    pub register_logs_list_state: ListState,
+    pub show_instruction_memory: bool, // Harvard only, Memory view shows fetch memory
+    pub focus: Focus,                  // Pane scrolled by Up/Down
+    pub status_selected: usize,        // CPU Status row to edit, PC then R0-R3
+    pub editing: Option<(EditTarget, String)>, // Value being typed into the status bar
+    pub console: Console,              // Command line opened with :
+    pub debugger: Debugger,
+    pub watchdog: Watchdog, // Pauses continuous runs that loop forever or run away
+    pub keymap: KeyMap,
+    pub show_help: bool,               // Help popup over everything else
+    pub register_logs_selected: usize, // Index into trace records
+    pub register_logs_offset: usize,   // First record shown
+    pub follow_register_logs: bool,    // Keep the newest record selected
     pub step_mode: bool,
+    pub micro_step: bool, // Steps are single register transfers
+    pub sequencer: MicroSequencer,
+    pub show_datapath: bool,    // Datapath diagram replaces CPU status
+    pub trace: Trace,           // Every completed instruction
+    pub highlights: Highlights, // Recently changed registers and memory
+    pub trace_path: String,
+    pub snapshot_path: String,
+    pub status: String,   // Message shown in the status bar
+    pub speed: usize,     // Index into SPEEDS
+    pub turbo: bool,      // Run as many steps as fit between frames
+    pub step_budget: f64, // Steps owed since the last frame at the current speed
+    pub last_tick: Instant,
+    pub ips: f64,                   // Measured instructions per second
+    pub ips_window: (Instant, u64), // Start of the measurement and instruction count then
     pub exit: bool,
 }
 
@@ -25,11 +80,37 @@ impl Default for App {
         Self {
             cpu: CPU::default(),
             program: Vec::default(),
+            pipeline: None,
+            control_unit: None,
             memory_list_state: ListState::default(),
-            register_logs: Vec::default(),
-            register_logs_list_state: ListState::default(),
+            show_instruction_memory: false,
+            focus: Focus::Memory,
+            status_selected: 0,
+            editing: None,
+            console: Console::default(),
+            debugger: Debugger::default(),
+            watchdog: Watchdog::default(),
+            keymap: KeyMap::default(),
+            show_help: false,
+            register_logs_selected: 0,
+            register_logs_offset: 0,
+            follow_register_logs: true,
             exit: false,
             step_mode: true, // Start in step mode by default
+            micro_step: false,
+            sequencer: MicroSequencer::default(),
+            show_datapath: false,
+            trace: Trace::default(),
+            highlights: Highlights::default(),
+            trace_path: String::from("trace.jsonl"),
+            snapshot_path: String::from("cpu.snapshot"),
+            status: String::new(),
+            speed: DEFAULT_SPEED,
+            turbo: false,
+            step_budget: 0.0,
+            last_tick: Instant::now(),
+            ips: 0.0,
+            ips_window: (Instant::now(), 0),
         }
     }
 }
@@ -51,18 +132,67 @@ impl App {
             // Non step mode operation
             // Step mode is defined in handle_key_event
             if !self.cpu.halted && !self.step_mode {
-                self.cpu.fetch();
-                let (opcode, register, operand) = self.cpu.decode();
-                self.cpu.execute(opcode, register, operand);
-                self.register_logs.push(self.cpu.log_registers());
-                self.memory_list_state
-                    .select(Some(self.cpu.pc.saturating_sub(1) as usize)); // Highlight current
+                self.run_continuous();
+            } else {
+                self.step_budget = 0.0;
             }
+            self.last_tick = Instant::now();
+            self.measure_ips();
         }
         Ok(())
     }
 
+    // Runs the steps due since the last frame, or everything that fits in a frame in turbo
+    fn run_continuous(&mut self) {
+        let now = Instant::now();
+
+        if self.turbo {
+            while !self.cpu.halted && !self.step_mode && now.elapsed() < FRAME_TIME {
+                for _ in 0..256 {
+                    if self.cpu.halted || self.step_mode {
+                        break;
+                    }
+                    self.advance();
+                }
+            }
+            return;
+        }
+
+        let elapsed = now.duration_since(self.last_tick).as_secs_f64();
+        self.step_budget += elapsed * SPEEDS[self.speed] as f64;
+        while self.step_budget >= 1.0
+            && !self.cpu.halted
+            && !self.step_mode
+            && now.elapsed() < FRAME_TIME
+        {
+            self.advance();
+            self.step_budget -= 1.0;
+        }
+        // Don't build up a backlog when the CPU can't keep up
+        self.step_budget = self.step_budget.min(1.0);
+    }
+
+    // Instructions per second, averaged over roughly a second
+    fn measure_ips(&mut self) {
+        let (start, instructions) = self.ips_window;
+        let elapsed = start.elapsed().as_secs_f64();
+        if elapsed >= 1.0 {
+            self.ips = self.cpu.instructions.saturating_sub(instructions) as f64 / elapsed;
+            self.ips_window = (Instant::now(), self.cpu.instructions);
+        }
+    }
+
     fn draw(&mut self, frame: &mut Frame) {
+        // Status bar along the bottom
+        let outer_layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(vec![
+                Constraint::Fill(1),
+                Constraint::Length(if self.console.open { 12 } else { 0 }),
+                Constraint::Length(1),
+            ])
+            .split(frame.area());
+
         // Define layout in terminal (3 Horizontally split panes)
         let main_layout = Layout::default()
             .direction(Direction::Horizontal)
@@ -71,19 +201,45 @@ impl App {
                 Constraint::Fill(1),
                 Constraint::Length(34),
             ])
-            .split(frame.area());
+            .split(outer_layout[0]);
 
         // == Memory List widget ==
-        let memory_items: Vec<ListItem> = self
-            .cpu
-            .memory
+        let (memory, memory_title) = match &self.cpu.instruction_memory {
+            Some(instruction_memory) if self.show_instruction_memory => {
+                (instruction_memory, "Memory view (instructions)")
+            }
+            Some(_) => (&self.cpu.memory, "Memory view (data)"),
+            None => (&self.cpu.memory, "Memory view"),
+        };
+        // Stores only land in data memory
+        let highlight_memory =
+            !(self.show_instruction_memory && self.cpu.instruction_memory.is_some());
+        let memory_items: Vec<ListItem> = memory
             .iter()
             .enumerate()
-            .map(|(i, &val)| ListItem::new(format!(" {:2} │ {:016b} │ {:5}", i, val, val)))
+            .map(|(i, &val)| {
+                // Breakpoints are marked next to the address
+                let marker = if self.debugger.breakpoints.contains(&(i as u16)) {
+                    '●'
+                } else {
+                    ' '
+                };
+                let item = ListItem::new(format!("{marker}{:2} │ {:016b} │ {:5}", i, val, val));
+                if highlight_memory {
+                    item.style(self.highlights.memory(i))
+                } else {
+                    item
+                }
+            })
             .collect();
 
         let memory_list_widget = List::new(memory_items)
-            .block(Block::default().borders(Borders::ALL).title("Memory view"))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(focus_style(self.focus == Focus::Memory))
+                    .title(memory_title),
+            )
             .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
 
         frame.render_stateful_widget(
@@ -93,95 +249,905 @@ impl App {
         );
         // =+= Memory List widget =+=
 
+        // Middle pane is split into CPU status, micro-ops, pipeline and cache (when enabled)
+        // and devices
+        let micro_height = if self.micro_step || self.control_unit.is_some() {
+            11
+        } else {
+            0
+        };
+        let pipeline_height = if self.pipeline.is_some() { 18 } else { 0 };
+        let cache_height = [&self.cpu.icache, &self.cpu.cache]
+            .iter()
+            .filter_map(|cache| cache.as_ref())
+            .map(cache_pane_height)
+            .fold(0, u16::saturating_add)
+            .min(main_layout[1].height);
+        let middle_layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(vec![
+                Constraint::Fill(1),
+                Constraint::Length(micro_height),
+                Constraint::Length(pipeline_height),
+                Constraint::Length(cache_height),
+                Constraint::Length(8),
+            ])
+            .split(main_layout[1]);
+
         // == CPU status widget ==
         let registers = self.cpu.get_all_registers();
-        let cpu_status_text = format!(
-            "PC: {} \nIR: {:016b}\n\nR0: {}\nR1: {}\nR2: {}\nR3: {} \n\nHalted: {}",
-            self.cpu.pc,
-            self.cpu.ir,
-            registers[0],
-            registers[1],
-            registers[2],
-            registers[3],
-            self.cpu.halted
-        );
+        let status_focused = self.focus == Focus::Status;
+        let selected = |row: usize, style: Style| {
+            if status_focused && row == self.status_selected {
+                style.add_modifier(Modifier::REVERSED)
+            } else {
+                style
+            }
+        };
+        let mut cpu_status_lines = vec![
+            Line::styled(
+                format!("PC: {}", self.cpu.pc),
+                selected(0, Style::default()),
+            ),
+            Line::from(format!("IR: {:016b}", self.cpu.ir)),
+            Line::from(format!("SP: {}", self.cpu.sp)),
+            Line::from(format!("MAR: {}  MDR: {}", self.cpu.mar, self.cpu.mdr)),
+            Line::from(format!(
+                "ALU A: {}  B: {}  OUT: {}",
+                self.cpu.alu_a, self.cpu.alu_b, self.cpu.alu_out
+            )),
+            Line::default(),
+        ];
+        // Registers written by recent instructions fade out over the next few steps
+        for (i, value) in registers.iter().enumerate() {
+            cpu_status_lines.push(Line::styled(
+                format!("R{i}: {value}"),
+                selected(i + 1, self.highlights.register(i)),
+            ));
+        }
+        cpu_status_lines.extend([
+            Line::default(),
+            Line::from(format!("Halted: {}", self.cpu.halted)),
+            Line::from(format!(
+                "In interrupt: {} (EPC: {})",
+                self.cpu.in_interrupt, self.cpu.epc
+            )),
+            Line::default(),
+            Line::from(format!("Instructions: {}", self.cpu.instructions)),
+            Line::from(format!("Cycles: {}", self.cpu.cycles)),
+            Line::from(format!("CPI: {:.2}", self.cpu.cpi())),
+        ]);
 
-        let cpu_status_paragraph = Paragraph::new(cpu_status_text)
-            .block(Block::default().borders(Borders::ALL).title("CPU Status"))
+        let cpu_status_paragraph = Paragraph::new(cpu_status_lines)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(focus_style(status_focused))
+                    .title("CPU Status"),
+            )
             .wrap(ratatui::widgets::Wrap { trim: false });
 
-        frame.render_widget(cpu_status_paragraph, main_layout[1]);
+        if !self.show_datapath {
+            frame.render_widget(cpu_status_paragraph, middle_layout[0]);
+        }
         // =+= CPU status widget =+=
 
+        // == Datapath widget ==
+        if self.show_datapath {
+            // Single transfer in micro-step mode, otherwise the whole last instruction
+            let (active, phase): (Vec<micro::MicroOp>, String) = match self.sequencer.active() {
+                // Microcoded: the last microinstruction, or everything run for the instruction
+                _ if let Some(control_unit) = &self.control_unit => {
+                    match control_unit.trace.last() {
+                        Some(&address) if self.micro_step => (
+                            control_unit.rom.words[address]
+                                .as_ref()
+                                .map_or(Vec::new(), |word| word.ops.clone()),
+                            format!("μPC {address}"),
+                        ),
+                        Some(_) => (control_unit.active_ops(), disassemble(self.cpu.ir)),
+                        None => (Vec::new(), String::from("idle")),
+                    }
+                }
+                Some(i) if self.micro_step => {
+                    let phase = if i < FETCH_SEQUENCE.len() - 1 {
+                        "fetch"
+                    } else if i == FETCH_SEQUENCE.len() - 1 {
+                        "decode"
+                    } else {
+                        "execute"
+                    };
+                    (vec![self.sequencer.ops[i]], phase.to_string())
+                }
+                // IR can hold a wrong-path word when pipelined
+                _ if self.cpu.instructions > 0
+                    && OpCode::u8_to_opcode(self.cpu.decode().0).is_some() =>
+                {
+                    let (opcode, _, _) = self.cpu.decode();
+                    let mut ops = FETCH_SEQUENCE.to_vec();
+                    ops.extend(micro::execute_sequence(opcode).unwrap_or_default());
+                    (ops, disassemble(self.cpu.ir))
+                }
+                _ => (Vec::new(), String::from("idle")),
+            };
+
+            frame.render_widget(
+                datapath::widget(&self.cpu, &active, &phase),
+                middle_layout[0],
+            );
+        }
+        // =+= Datapath widget =+=
+
+        // == Micro-ops widget ==
+        if let Some(control_unit) = &self.control_unit {
+            // Microinstructions run for the current instruction, latest highlighted
+            let micro_items: Vec<ListItem> = control_unit
+                .trace
+                .iter()
+                .filter_map(|&address| Some((address, control_unit.rom.words[address].as_ref()?)))
+                .map(|(address, word)| {
+                    let ops: Vec<String> = word
+                        .ops
+                        .iter()
+                        .map(|&op| micro::describe(op, self.cpu.ir))
+                        .collect();
+                    let sequence = match word.sequence {
+                        Sequence::Next => String::new(),
+                        Sequence::Goto(target) => format!(" → {target}"),
+                        Sequence::Dispatch => String::from(" → dispatch"),
+                        Sequence::End => String::from(" → end"),
+                    };
+                    ListItem::new(format!("{:3}: {}{}", address, ops.join("; "), sequence))
+                })
+                .collect();
+
+            let mut micro_list_state = ListState::default();
+            micro_list_state.select(control_unit.trace.len().checked_sub(1));
+
+            // IR only names the instruction once the fetch routine has dispatched
+            let dispatched = control_unit.trace.iter().any(|&address| {
+                control_unit.rom.words[address]
+                    .as_ref()
+                    .is_some_and(|word| matches!(word.sequence, Sequence::Dispatch))
+            });
+            let opcode = (self.cpu.ir >> 12) as usize;
+            let title = match &control_unit.rom.mnemonics[opcode] {
+                Some(mnemonic) if dispatched => {
+                    format!("Microcode ({mnemonic}, next μPC {})", control_unit.upc)
+                }
+                _ => format!("Microcode (next μPC {})", control_unit.upc),
+            };
+            let micro_list_widget = List::new(micro_items)
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+            frame.render_stateful_widget(
+                micro_list_widget,
+                middle_layout[1],
+                &mut micro_list_state,
+            );
+        } else if self.micro_step {
+            let active = self.sequencer.active();
+            let micro_items: Vec<ListItem> = self
+                .sequencer
+                .ops
+                .iter()
+                .enumerate()
+                .map(|(i, &op)| {
+                    let marker = if Some(i) == active { "▶" } else { " " };
+                    ListItem::new(format!("{} {}", marker, micro::describe(op, self.cpu.ir)))
+                })
+                .collect();
+
+            let mut micro_list_state = ListState::default();
+            micro_list_state.select(active);
+
+            let micro_list_widget = List::new(micro_items)
+                .block(Block::default().borders(Borders::ALL).title("Micro-ops"))
+                .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+            frame.render_stateful_widget(
+                micro_list_widget,
+                middle_layout[1],
+                &mut micro_list_state,
+            );
+        }
+        // =+= Micro-ops widget =+=
+
+        // == Devices widget ==
+        let timer = &self.cpu.timer;
+        let devices_text = format!(
+            "Timer\nReload: {:5}  Count: {:5}\nControl: {:04b} [{}]\nVector: {}\nIRQ: {}",
+            timer.reload,
+            timer.count,
+            timer.control,
+            timer.control_flags(),
+            timer.vector,
+            if timer.irq() { "raised" } else { "idle" }
+        );
+
+        let devices_paragraph = Paragraph::new(devices_text)
+            .block(Block::default().borders(Borders::ALL).title("Devices"));
+
+        frame.render_widget(devices_paragraph, middle_layout[4]);
+        // =+= Devices widget =+=
+
+        // == Pipeline widget ==
+        if let Some(pipeline) = &self.pipeline {
+            let mut lines = vec![format!(
+                "Forwarding: {}  Stalls: {}  Flushed: {}  Miss: {}  Port: {}",
+                if pipeline.forwarding { "on" } else { "off" },
+                pipeline.stalls,
+                pipeline.flushed_cycles,
+                pipeline.memory_stalls,
+                pipeline.structural_stalls
+            )];
+
+            // Instruction in each stage during the last cycle
+            if let Some(last) = pipeline.history.last() {
+                for (name, slot) in STAGE_NAMES.iter().zip(last.stages) {
+                    lines.push(match slot {
+                        Some(slot) => {
+                            format!("{:>3}: {:2} {}", name, slot.pc, disassemble(slot.ir))
+                        }
+                        None => format!("{:>3}: -", name),
+                    });
+                }
+            }
+
+            // Diagram of PCs per stage, newest cycle at the bottom
+            lines.push(String::from(" Cyc │  IF │  ID │  EX │ MEM │  WB"));
+            let rows = (pipeline_height as usize).saturating_sub(lines.len() + 2);
+            let start = pipeline.history.len().saturating_sub(rows);
+            for record in &pipeline.history[start..] {
+                let cells: Vec<String> = record
+                    .stages
+                    .iter()
+                    .map(|slot| match slot {
+                        Some(slot) => format!("{:3}", slot.pc),
+                        None => String::from("  -"),
+                    })
+                    .collect();
+                let note = if record.waiting {
+                    " miss"
+                } else if record.structural {
+                    " port"
+                } else if record.stalled {
+                    " stall"
+                } else if record.flushed {
+                    " flush"
+                } else {
+                    ""
+                };
+                lines.push(format!(
+                    "{:4} │ {} │ {} │ {} │ {} │ {}{}",
+                    record.cycle, cells[0], cells[1], cells[2], cells[3], cells[4], note
+                ));
+            }
+
+            let pipeline_paragraph = Paragraph::new(lines.join("\n"))
+                .block(Block::default().borders(Borders::ALL).title("Pipeline"));
+
+            frame.render_widget(pipeline_paragraph, middle_layout[2]);
+        }
+        // =+= Pipeline widget =+=
+
+        // == Cache widget ==
+        // Split I/D caches are stacked, instruction cache first
+        let caches: Vec<(&Cache, &str)> = match (&self.cpu.icache, &self.cpu.cache) {
+            (Some(icache), Some(dcache)) => vec![(icache, "I-cache"), (dcache, "D-cache")],
+            (None, Some(cache)) => vec![(cache, "Cache")],
+            _ => Vec::new(),
+        };
+        let cache_layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(
+                caches
+                    .iter()
+                    .map(|(cache, _)| Constraint::Length(cache_pane_height(cache))),
+            )
+            .split(middle_layout[3]);
+
+        for (i, (cache, title)) in caches.into_iter().enumerate() {
+            let cache_paragraph = Paragraph::new(cache_lines(cache).join("\n"))
+                .block(Block::default().borders(Borders::ALL).title(title));
+
+            frame.render_widget(cache_paragraph, cache_layout[i]);
+        }
+        // =+= Cache widget =+=
+
+        // Right pane is split into register logs and branch predictor stats (when pipelined)
+        let predictor_height = if self.pipeline.is_some() { 12 } else { 0 };
+        let right_layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(vec![
+                Constraint::Fill(1),
+                Constraint::Length(predictor_height),
+            ])
+            .split(main_layout[2]);
+
         // == Register Logs widget ==
-        let register_logs_item: Vec<ListItem> = self
-            .register_logs
-            .iter()
-            .enumerate()
-            .map(|(_, log)| ListItem::new(log.clone()))
+        let register_logs_block = Block::default()
+            .borders(Borders::ALL)
+            .border_style(focus_style(self.focus == Focus::RegisterLogs))
+            .title(if self.follow_register_logs {
+                "Register Logs"
+            } else {
+                "Register Logs (scrolled)"
+            });
+        let register_logs_area = register_logs_block.inner(right_layout[0]);
+        frame.render_widget(register_logs_block, right_layout[0]);
+
+        let register_logs_layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(vec![Constraint::Length(1), Constraint::Fill(1)])
+            .split(register_logs_area);
+        frame.render_widget(
+            Paragraph::new(" PC │   R0 │   R1 │   R2 │   R3")
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+            register_logs_layout[0],
+        );
+
+        // Only the visible rows are built, the trace can be long
+        let records = &self.trace.records;
+        let rows = register_logs_layout[1].height as usize;
+        if self.follow_register_logs {
+            self.register_logs_selected = records.len().saturating_sub(1);
+        }
+        if self.register_logs_selected < self.register_logs_offset {
+            self.register_logs_offset = self.register_logs_selected;
+        } else if rows > 0 && self.register_logs_selected >= self.register_logs_offset + rows {
+            self.register_logs_offset = self.register_logs_selected + 1 - rows;
+        }
+
+        let register_logs_items: Vec<ListItem> = records
+            .range(self.register_logs_offset.min(records.len())..)
+            .take(rows)
+            .map(register_log_row)
             .collect();
 
-        let register_logs_widget = List::new(register_logs_item)
-            .block(
-                Block::default()
-                    .borders(Borders::ALL)
-                    .title("Register Logs"),
-            )
+        let mut register_logs_list_state = ListState::default();
+        if !records.is_empty() {
+            register_logs_list_state.select(Some(
+                self.register_logs_selected - self.register_logs_offset,
+            ));
+        }
+
+        let register_logs_widget = List::new(register_logs_items)
             .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
 
         frame.render_stateful_widget(
             register_logs_widget,
-            main_layout[2],
-            &mut self.register_logs_list_state,
+            register_logs_layout[1],
+            &mut register_logs_list_state,
         );
         // =+= Register Logs widget =+=
+
+        // == Branch predictor widget ==
+        if let Some(pipeline) = &self.pipeline {
+            let predictor = &pipeline.predictor;
+            let total = predictor.total();
+
+            let mut lines = vec![
+                format!("{}", predictor.kind.name()),
+                format!(
+                    "Branches: {}  Accuracy: {:.1}%",
+                    total.executed,
+                    total.accuracy()
+                ),
+                String::from(" Addr │ Exec │ Taken │   Acc"),
+            ];
+            for (address, stats) in &predictor.stats {
+                lines.push(format!(
+                    " {:4} │ {:4} │ {:5} │ {:4.0}%",
+                    address,
+                    stats.executed,
+                    stats.taken,
+                    stats.accuracy()
+                ));
+            }
+
+            let predictor_paragraph = Paragraph::new(lines.join("\n")).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Branch Predictor"),
+            );
+
+            frame.render_widget(predictor_paragraph, right_layout[1]);
+        }
+        // =+= Branch predictor widget =+=
+
+        // == Status bar widget ==
+        let mode = if self.step_mode { "Step" } else { "Continuous" };
+        let speed = if self.turbo {
+            String::from("turbo")
+        } else {
+            format!("{}/s", SPEEDS[self.speed])
+        };
+        let mut status_text = match &self.editing {
+            Some((target, text)) => format!(
+                " Edit {target} (now {}) = {text}█",
+                target.current(&self.cpu)
+            ),
+            None => format!(" {mode} mode │ Speed: {speed} │ {:.0} IPS", self.ips),
+        };
+        if !self.status.is_empty() {
+            status_text.push_str(&format!(" │ {}", self.status));
+        }
+        frame.render_widget(Paragraph::new(status_text), outer_layout[2]);
+        // =+= Status bar widget =+=
+
+        // == Console widget ==
+        if self.console.open {
+            // Latest output above the prompt
+            let rows = (outer_layout[1].height as usize).saturating_sub(3);
+            let start = self.console.output.len().saturating_sub(rows);
+            let mut lines: Vec<Line> = self.console.output[start..]
+                .iter()
+                .map(|line| Line::from(line.as_str()))
+                .collect();
+            lines.push(Line::from(format!("(sim) {}█", self.console.input)));
+
+            let console_paragraph = Paragraph::new(lines).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(focus_style(true))
+                    .title("Console (help for commands, Esc to close)"),
+            );
+            frame.render_widget(console_paragraph, outer_layout[1]);
+        }
+        // =+= Console widget =+=
+
+        // == Help widget ==
+        if self.show_help {
+            let mut lines: Vec<Line> = self
+                .keymap
+                .help()
+                .into_iter()
+                .map(|(keys, description)| {
+                    let keys = if keys.is_empty() {
+                        String::from("(unbound)")
+                    } else {
+                        keys
+                    };
+                    Line::from(vec![
+                        Span::styled(
+                            format!(" {keys:12}"),
+                            Style::default().add_modifier(Modifier::BOLD),
+                        ),
+                        Span::raw(description),
+                    ])
+                })
+                .collect();
+            lines.extend([
+                Line::default(),
+                Line::from(
+                    " In the console: Enter runs, Tab completes, Up/Down history, Esc closes",
+                ),
+                Line::from(" While editing: Enter applies, Esc cancels"),
+            ]);
+
+            // Centred, clipped to the screen
+            let area = frame.area();
+            let width = 80.min(area.width);
+            let height = (lines.len() as u16 + 2).min(area.height);
+            let popup = ratatui::layout::Rect {
+                x: area.x + (area.width - width) / 2,
+                y: area.y + (area.height - height) / 2,
+                width,
+                height,
+            };
+            frame.render_widget(Clear, popup);
+            frame.render_widget(
+                Paragraph::new(lines).block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .border_style(focus_style(true))
+                        .title("Help (any key to close)"),
+                ),
+                popup,
+            );
+        }
+        // =+= Help widget =+=
     }
 
     fn handle_events(&mut self) -> io::Result<()> {
         // Poll to prevent blocking
-        if event::poll(std::time::Duration::from_millis(50))? {
-            if let Event::Key(key_event) = event::read()? {
-                if key_event.kind == KeyEventKind::Press {
-                    self.handle_key_event(key_event);
-                }
-            }
+        // Shorter wait while running so steps are spread evenly over the second
+        let timeout = if self.step_mode || self.cpu.halted {
+            Duration::from_millis(50)
+        } else {
+            FRAME_TIME
+        };
+        if event::poll(timeout)?
+            && let Event::Key(key_event) = event::read()?
+            && key_event.kind == KeyEventKind::Press
+        {
+            self.handle_key_event(key_event);
         }
         Ok(())
     }
 
     fn handle_key_event(&mut self, key_event: KeyEvent) {
-        match key_event.code {
-            KeyCode::Enter => self.step(),
-            KeyCode::Char('q') => self.exit = true,
-            KeyCode::Char('r') => self.reset_cpu(),
-            KeyCode::Up => self.scroll_memory_up(),
-            KeyCode::Down => self.scroll_memory_down(),
-            KeyCode::Char('t') => {
+        if self.console.open {
+            self.handle_console_key(key_event);
+            return;
+        }
+        if self.editing.is_some() {
+            self.handle_edit_key(key_event);
+            return;
+        }
+
+        if self.show_help {
+            self.show_help = false; // Any key closes help
+            return;
+        }
+
+        let Some(action) = self.keymap.action(key_event) else {
+            return;
+        };
+        match action {
+            Action::Step => self.step(),
+            Action::Quit => self.exit = true,
+            Action::Reset => self.reset_cpu(),
+            Action::SaveSnapshot => self.save_snapshot(),
+            Action::LoadSnapshot => self.load_snapshot(),
+            Action::ExportTrace => self.export_trace(),
+            Action::Edit => self.start_edit(),
+            Action::Console => self.console.open = true,
+            Action::Help => self.show_help = true,
+            Action::ToggleBreakpoint => self.toggle_breakpoint(),
+            Action::RunToCursor => {
+                let address = self.memory_list_state.selected().unwrap_or(0) as u16;
+                self.execute_command(Command::Until(address));
+            }
+            Action::StepOver => self.execute_command(Command::Next),
+            Action::StepOut => self.execute_command(Command::Finish),
+            Action::Faster => {
+                self.speed = (self.speed + 1).min(SPEEDS.len() - 1);
+                self.turbo = false;
+            }
+            Action::Slower => {
+                self.speed = self.speed.saturating_sub(1);
+                self.turbo = false;
+            }
+            Action::Turbo => self.turbo = !self.turbo,
+            Action::Focus => {
+                self.focus = match self.focus {
+                    Focus::Memory => Focus::Status,
+                    Focus::Status => Focus::RegisterLogs,
+                    Focus::RegisterLogs => Focus::Memory,
+                };
+            }
+            Action::Up if self.focus == Focus::Status => {
+                self.status_selected = self.status_selected.saturating_sub(1);
+            }
+            Action::Down if self.focus == Focus::Status => {
+                self.status_selected = (self.status_selected + 1).min(4);
+            }
+            Action::Up if self.focus == Focus::RegisterLogs => self.scroll_register_logs_up(),
+            Action::Down if self.focus == Focus::RegisterLogs => self.scroll_register_logs_down(),
+            Action::Up => self.scroll_memory_up(),
+            Action::Down => self.scroll_memory_down(),
+            Action::MicroStep => {
+                if self.pipeline.is_none() {
+                    self.micro_step = !self.micro_step;
+                }
+            }
+            Action::Datapath => {
+                self.show_datapath = !self.show_datapath;
+            }
+            Action::InstructionMemory => {
+                self.show_instruction_memory = !self.show_instruction_memory;
+            }
+            Action::ToggleMode => {
                 self.step_mode = !self.step_mode; // Toggle
+                self.debugger.cancel();
+                self.watchdog.restart();
+            }
+        }
+    }
+
+    fn toggle_breakpoint(&mut self) {
+        let address = self.memory_list_state.selected().unwrap_or(0) as u16;
+        if self.debugger.breakpoints.remove(&address) {
+            self.status = format!("Deleted breakpoint at {address}");
+        } else {
+            self.debugger.breakpoints.insert(address);
+            self.status = format!("Breakpoint at {address}");
+        }
+    }
+
+    // Edit the selected memory word, or the selected register in the CPU Status pane
+    fn start_edit(&mut self) {
+        let target = match self.focus {
+            Focus::Memory => EditTarget::Memory {
+                address: self.memory_list_state.selected().unwrap_or(0),
+                instruction: self.show_instruction_memory && self.cpu.instruction_memory.is_some(),
+            },
+            Focus::Status if self.status_selected == 0 => EditTarget::Pc,
+            Focus::Status => EditTarget::Register(self.status_selected as u8 - 1),
+            Focus::RegisterLogs => return,
+        };
+        if target == EditTarget::Pc && self.in_flight() {
+            self.status = String::from("Can't move the PC in the middle of an instruction");
+            return;
+        }
+
+        self.status.clear();
+        self.editing = Some((target, String::new()));
+    }
+
+    fn handle_edit_key(&mut self, key_event: KeyEvent) {
+        let Some((target, text)) = &mut self.editing else {
+            return;
+        };
+
+        match key_event.code {
+            KeyCode::Char(c) => text.push(c),
+            KeyCode::Backspace => {
+                text.pop();
             }
+            KeyCode::Esc => self.editing = None,
+            KeyCode::Enter => match edit::parse_value(text) {
+                Ok(value) => {
+                    let target = *target;
+                    target.apply(&mut self.cpu, value);
+                    self.trace.sync_registers(&self.cpu);
+                    self.watchdog.restart();
+                    if target == EditTarget::Pc {
+                        self.memory_list_state
+                            .select(Some(self.cpu.pc.saturating_sub(1) as usize));
+                    }
+                    self.status = match target {
+                        EditTarget::Memory { .. } => {
+                            format!("{target} = {value} ({})", disassemble(value))
+                        }
+                        _ => format!("{target} = {value}"),
+                    };
+                    self.editing = None;
+                }
+                // Keep the text so it can be corrected
+                Err(e) => self.status = e,
+            },
             _ => {}
         }
     }
 
+    fn handle_console_key(&mut self, key_event: KeyEvent) {
+        match key_event.code {
+            KeyCode::Char(c) => self.console.input.push(c),
+            KeyCode::Backspace => {
+                self.console.input.pop();
+            }
+            KeyCode::Esc => self.console.open = false,
+            KeyCode::Up => self.console.history_up(),
+            KeyCode::Down => self.console.history_down(),
+            KeyCode::Tab => self.console.complete(),
+            KeyCode::Enter => {
+                let line = self.console.submit();
+                if !line.trim().is_empty() {
+                    self.run_command(&line);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn run_command(&mut self, line: &str) {
+        match debugger::parse(line) {
+            Ok(command) => self.execute_command(command),
+            Err(e) => self.console.print(e),
+        }
+    }
+
+    // Run commands switch to continuous mode until the debugger stops them
+    fn execute_command(&mut self, command: Command) {
+        let result = if matches!(command, Command::Set(EditTarget::Pc, _)) && self.in_flight() {
+            Err(String::from(
+                "Can't move the PC in the middle of an instruction",
+            ))
+        } else {
+            let position = Position::of(&self.cpu, self.pipeline.as_ref());
+            self.debugger.execute(command, &mut self.cpu, &position)
+        };
+
+        match result {
+            Ok(lines) => {
+                for line in lines {
+                    self.console.print(line);
+                }
+                self.trace.sync_registers(&self.cpu);
+                self.watchdog.restart();
+                if self.debugger.running() {
+                    self.step_mode = false;
+                    self.status = String::from("Running");
+                }
+            }
+            Err(e) => {
+                self.console.print(e.clone());
+                self.status = e;
+            }
+        }
+    }
+
     fn step(&mut self) {
         if self.step_mode && !self.cpu.halted {
-            self.cpu.fetch();
-            let (opcode, register, operand) = self.cpu.decode();
-            self.cpu.execute(opcode, register, operand);
-            self.register_logs.push(self.cpu.log_registers());
+            self.advance();
         }
         self.memory_list_state
             .select(Some(self.cpu.pc.saturating_sub(1) as usize)); // Highlight current
         // instruction in memory
     }
 
+    // Execute one instruction, or one clock cycle when pipelined
+    fn advance(&mut self) {
+        let completed = match &mut self.pipeline {
+            // Only trace once an instruction leaves WB
+            Some(pipeline) => match pipeline.cycle(&mut self.cpu) {
+                Some(slot) => {
+                    let writes = slot.memory_write().into_iter().collect();
+                    self.trace.record(&self.cpu, slot.pc, slot.ir, writes);
+                    true
+                }
+                None => false,
+            },
+            None if let Some(control_unit) = &mut self.control_unit => {
+                let done = if self.micro_step {
+                    control_unit.micro_step(&mut self.cpu)
+                } else {
+                    control_unit.step(&mut self.cpu);
+                    true
+                };
+                if done {
+                    self.trace.record_step(&self.cpu);
+                }
+                done
+            }
+            None if self.micro_step => {
+                let done = self.sequencer.micro_step(&mut self.cpu);
+                if done {
+                    self.trace.record_step(&self.cpu);
+                }
+                done
+            }
+            None => {
+                // Finish an instruction left half done by micro-step mode
+                if self.sequencer.in_progress() {
+                    while !self.cpu.halted && !self.sequencer.micro_step(&mut self.cpu) {}
+                } else {
+                    self.cpu.step();
+                }
+                self.trace.record_step(&self.cpu);
+                true
+            }
+        };
+
+        // Said once, when the first record falls off the front
+        if completed
+            && self.trace.dropped == 1
+            && let Some(warning) = self.trace.truncated()
+        {
+            self.status = warning;
+        }
+        if completed && let Some(record) = self.trace.records.back() {
+            self.highlights.update(record);
+
+            let position = Position::of(&self.cpu, self.pipeline.as_ref());
+            // A fault goes through the watchdog even in step mode, it ends any run
+            if self.cpu.fault.is_none()
+                && let Some(reason) = self.debugger.after_step(&self.cpu, &position, record)
+            {
+                self.step_mode = true;
+                self.console.print(reason.clone());
+                self.status = reason;
+            } else if (!self.step_mode || self.cpu.fault.is_some())
+                && let Some(reason) = self.watchdog.check(&self.cpu, record.pc, position.next_pc)
+            {
+                self.step_mode = true;
+                self.debugger.cancel();
+                self.console.print(reason.clone());
+                self.status = reason;
+            }
+        }
+        self.memory_list_state
+            .select(Some(self.cpu.pc.saturating_sub(1) as usize)); // Highlight current
+    }
+
     fn reset_cpu(&mut self) {
-        self.cpu.halted = false;
-        self.cpu.pc = 0;
-        self.register_logs.clear();
-        self.cpu.memory = [0u16; 64];
+        self.debugger.cancel();
+        self.watchdog.restart();
+        self.follow_register_logs = true;
         self.memory_list_state = ListState::default();
-        self.cpu = CPU::new(&self.program);
+        self.cpu.reset(&self.program);
+        self.trace.reset(&self.cpu);
+        self.highlights.clear();
+        self.sequencer = MicroSequencer::default();
+        if let Some(control_unit) = &mut self.control_unit {
+            control_unit.reset();
+        }
+        if let Some(pipeline) = &mut self.pipeline {
+            pipeline.reset();
+        }
+    }
+
+    // An instruction is partly through the pipeline, sequencer or control unit
+    fn in_flight(&self) -> bool {
+        self.pipeline
+            .as_ref()
+            .is_some_and(|pipeline| !pipeline.is_empty())
+            || self.sequencer.in_progress()
+            || self
+                .control_unit
+                .as_ref()
+                .is_some_and(|control_unit| control_unit.in_progress())
+    }
+
+    // Snapshots are taken between instructions
+    fn save_snapshot(&mut self) {
+        if self.in_flight() {
+            self.status = String::from("Can't save a snapshot in the middle of an instruction");
+            return;
+        }
+
+        self.status = match snapshot::save(&self.cpu, &self.snapshot_path) {
+            Ok(()) => format!("Saved snapshot to {}", self.snapshot_path),
+            Err(e) => format!("Couldn't save snapshot: {e}"),
+        };
+    }
+
+    fn load_snapshot(&mut self) {
+        if let Err(e) = snapshot::load(&mut self.cpu, &self.snapshot_path) {
+            self.status = format!("Couldn't load snapshot: {e}");
+            return;
+        }
+
+        self.sequencer = MicroSequencer::default();
+        if let Some(control_unit) = &mut self.control_unit {
+            control_unit.reset();
+        }
+        if let Some(pipeline) = &mut self.pipeline {
+            pipeline.reset();
+        }
+        self.trace.reset(&self.cpu);
+        self.highlights.clear();
+        self.watchdog.restart();
+        self.follow_register_logs = true;
+        self.memory_list_state
+            .select(Some(self.cpu.pc.saturating_sub(1) as usize));
+        self.status = format!("Loaded snapshot from {}", self.snapshot_path);
+    }
+
+    fn export_trace(&mut self) {
+        self.status = match self.trace.export(&self.trace_path) {
+            Ok(()) if self.trace.dropped > 0 => format!(
+                "Exported the last {} instructions to {}, the first {} were dropped",
+                self.trace.records.len(),
+                self.trace_path,
+                self.trace.dropped
+            ),
+            Ok(()) => format!(
+                "Exported {} instructions to {}",
+                self.trace.records.len(),
+                self.trace_path
+            ),
+            Err(e) => format!("Couldn't export trace: {e}"),
+        };
+    }
+
+    // Scrolling away from the newest record stops following, scrolling back resumes it
+    fn scroll_register_logs_up(&mut self) {
+        if self.follow_register_logs {
+            self.register_logs_selected = self.trace.records.len().saturating_sub(1);
+        }
+        self.register_logs_selected = self.register_logs_selected.saturating_sub(1);
+        self.follow_register_logs = false;
+    }
+
+    fn scroll_register_logs_down(&mut self) {
+        let last = self.trace.records.len().saturating_sub(1);
+        self.register_logs_selected = (self.register_logs_selected + 1).min(last);
+        self.follow_register_logs = self.register_logs_selected == last;
     }
 
     fn scroll_memory_up(&mut self) {
@@ -212,3 +1178,70 @@ impl App {
         self.memory_list_state.select(Some(i));
     }
 }
+
+fn focus_style(focused: bool) -> Style {
+    if focused {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default()
+    }
+}
+
+// PC and registers after one instruction, registers it changed are highlighted
+fn register_log_row(record: &TraceRecord) -> ListItem<'static> {
+    let mut spans = vec![Span::raw(format!(" {:2} ", record.pc))];
+    for (register, value) in record.registers.iter().enumerate() {
+        let changed = record
+            .register_changes
+            .iter()
+            .any(|&(changed, _, _)| changed as usize == register);
+        let style = if changed {
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD)
+        } else {
+            Style::default()
+        };
+        spans.push(Span::raw("│ "));
+        spans.push(Span::styled(format!("{value:4}"), style));
+        spans.push(Span::raw(" "));
+    }
+    ListItem::new(Line::from(spans))
+}
+
+// Cache contents as text, one line per set/way plus a summary
+// Every line plus the summary and borders
+fn cache_pane_height(cache: &Cache) -> u16 {
+    u16::try_from(cache.config.lines)
+        .unwrap_or(u16::MAX)
+        .saturating_add(4)
+}
+
+fn cache_lines(cache: &Cache) -> Vec<String> {
+    let mut lines = vec![
+        cache.config.describe(),
+        format!(
+            "Hits: {}  Misses: {}  Hit rate: {:.1}%  Writebacks: {}",
+            cache.hits,
+            cache.misses,
+            cache.hit_rate(),
+            cache.writebacks
+        ),
+    ];
+
+    for (set_index, set) in cache.sets.iter().enumerate() {
+        for (way, line) in set.iter().enumerate() {
+            let data: Vec<String> = line.data.iter().map(|word| format!(" {word:5}")).collect();
+            lines.push(format!(
+                " {:2}.{} │ {}{} │ tag {:3} │{}",
+                set_index,
+                way,
+                if line.valid { 'V' } else { '-' },
+                if line.dirty { 'D' } else { '-' },
+                line.tag,
+                data.join("")
+            ));
+        }
+    }
+    lines
+}
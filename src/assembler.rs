@@ -1,6 +1,16 @@
-use std::fs::File;
-use std::io::{Read, Write};
+#![allow(clippy::upper_case_acronyms)]
 
+mod syntax;
+
+use crate::syntax::{literal_char, parse_literal, parse_register, shown, split_operands, unquoted};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+#[derive(Clone, Copy)]
 enum OpCode {
     HALT = 0b0000,
     LVAL = 0b0001, // Load immediate value into register
@@ -9,293 +19,1285 @@ enum OpCode {
     ADD = 0b0100,
     SUB = 0b0101,
     JMP = 0b0110,
-    MOV = 0b0111, // Move register's value to another register
+    MOV = 0b0111,  // Move register's value to another register
+    RETI = 0b1000, // Return from interrupt handler
+    JNZ = 0b1001,  // Jump if register is not zero
+    CALL = 0b1010, // Push return address and jump
+    RET = 0b1011,  // Pop return address
 }
 
-enum Register {
-    R0 = 0b0000,
-    R1 = 0b0001,
-    R2 = 0b0010,
-    R3 = 0b0011,
-}
+// Width of the operand field, [4 opcode][4 register][8 operand]
+const OPERAND_BITS: u32 = 8;
+const WORD_BITS: u32 = 16;
 
-fn main() {
-    let mut args = std::env::args();
-    let filename = args.nth(1).unwrap();
+// Largest image, the size of the simulator's memory
+const MEMORY_WORDS: usize = 64;
 
-    match std::fs::File::open(filename) {
-        Ok(mut file) => {
-            let mut output_file = File::create("program.bin").unwrap();
+// Deepest chain of macros using macros
+const MAX_MACRO_DEPTH: usize = 16;
 
-            let mut source = String::new();
-            file.read_to_string(&mut source).unwrap();
+const MNEMONICS: [&str; 12] = [
+    "HALT", "LVAL", "LOAD", "STORE", "ADD", "SUB", "JMP", "MOV", "RETI", "JNZ", "CALL", "RET",
+];
 
-            let lines: Vec<&str> = source.lines().collect();
+fn main() {
+    // assembler [-I DIR]... FILE.asm, include directories are searched in order
+    let mut include_paths = Vec::new();
+    let mut filename = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-I" => match args.next() {
+                Some(directory) => include_paths.push(PathBuf::from(directory)),
+                None => usage(),
+            },
+            _ if arg.starts_with("-I") => include_paths.push(PathBuf::from(&arg[2..])),
+            _ if arg.starts_with('-') || filename.is_some() => usage(),
+            _ => filename = Some(arg),
+        }
+    }
+    let Some(filename) = filename else {
+        usage();
+    };
 
-            for line in lines {
-                let first_char = line.chars().next().unwrap_or(';');
+    // A file that can't be read or written is reported like a usage problem
+    let source = fs::read_to_string(&filename).unwrap_or_else(|e| {
+        eprintln!("{filename}: {e}");
+        std::process::exit(2);
+    });
 
-                // Ignore lines that starts with ; (comments) and blank lines
-                if first_char == ';' || first_char == '\n' {
-                    continue;
+    // Every bad line is reported, nothing is written unless all of them assemble
+    let program = match assemble(&filename, &source, include_paths) {
+        Ok(program) => program,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}: {}", error.location, error.message);
+                for (location, note) in error.notes {
+                    eprintln!("{location}: {note}");
                 }
+            }
+            std::process::exit(1);
+        }
+    };
 
-                // HALT does not contain any argument
-                let trimmed_line = line.trim();
-                let (instruction, _) = match trimmed_line.find(char::is_whitespace) {
-                    Some(i) => trimmed_line.split_at(i),
-                    None => (trimmed_line, ""),
-                };
+    let bytes: Vec<u8> = program.iter().flat_map(|word| word.to_be_bytes()).collect();
+    if let Err(e) = fs::write("program.bin", bytes) {
+        eprintln!("program.bin: {e}");
+        std::process::exit(2);
+    }
+}
 
-                let instruction = instruction.trim();
+fn usage() -> ! {
+    eprintln!("Usage: assembler [-I DIR]... FILE.asm");
+    std::process::exit(2);
+}
 
-                match instruction {
-                    "HALT" => {
-                        let binary_instruction = (OpCode::HALT as u16) << 12;
+// NOTE: Memory image
+// Words placed from the location counter, which .org can move anywhere. Gaps are
+// zero, and placing a word where an earlier line already put one is an error.
+#[derive(Default)]
+struct Image {
+    words: Vec<u16>,
+    lines: Vec<Option<Location>>, // Line that placed each word
+    location: usize,
+}
 
-                        output_file
-                            .write(binary_instruction.to_be_bytes().as_slice())
-                            .unwrap();
-                    }
-                    "LVAL" => {
-                        let (_, args) = line.split_once(char::is_whitespace).unwrap();
-                        let (arg1, arg2) = args.split_once(", ").unwrap();
-                        let mut binary_instructions = (OpCode::LVAL as u16) << 12;
-
-                        match arg1 {
-                            "R0" => {
-                                binary_instructions |= (Register::R0 as u16) << 8;
-                            }
-                            "R1" => {
-                                binary_instructions |= (Register::R1 as u16) << 8;
-                            }
-                            "R2" => {
-                                binary_instructions |= (Register::R2 as u16) << 8;
-                            }
-                            "R3" => {
-                                binary_instructions |= (Register::R3 as u16) << 8;
-                            }
-                            _ => {
-                                panic!("Unknown register {arg1}")
-                            }
-                        }
+impl Image {
+    fn emit(&mut self, words: &[u16], line: &Location) -> Result<(), String> {
+        let end = self.location + words.len();
+        if end > MEMORY_WORDS {
+            return Err(format!(
+                "Doesn't fit in memory, {MEMORY_WORDS} words from 0 to {}",
+                MEMORY_WORDS - 1
+            ));
+        }
+        if let Some((address, Some(other))) = self
+            .lines
+            .iter()
+            .enumerate()
+            .take(end)
+            .skip(self.location)
+            .find(|(_, owner)| owner.is_some())
+        {
+            return Err(format!(
+                "Overlaps address {address}, already assembled at {other}"
+            ));
+        }
 
-                        binary_instructions |= arg2.to_string().parse::<u16>().unwrap();
+        if self.words.len() < end {
+            self.words.resize(end, 0);
+            self.lines.resize(end, None);
+        }
+        self.words[self.location..end].copy_from_slice(words);
+        self.lines[self.location..end].fill(Some(line.clone()));
+        self.location = end;
+        Ok(())
+    }
+}
 
-                        output_file
-                            .write(binary_instructions.to_be_bytes().as_slice())
-                            .unwrap();
-                    }
-                    "LOAD" => {
-                        let (_, args) = line.split_once(char::is_whitespace).unwrap();
-                        let (arg1, arg2) = args.split_once(", ").unwrap();
-                        let mut binary_instructions = (OpCode::LOAD as u16) << 12;
-
-                        match arg1 {
-                            "R0" => {
-                                binary_instructions |= (Register::R0 as u16) << 8;
-                            }
-                            "R1" => {
-                                binary_instructions |= (Register::R1 as u16) << 8;
-                            }
-                            "R2" => {
-                                binary_instructions |= (Register::R2 as u16) << 8;
-                            }
-                            "R3" => {
-                                binary_instructions |= (Register::R3 as u16) << 8;
-                            }
-                            _ => {
-                                panic!("Unknown register {arg1}")
-                            }
-                        }
+// A line in a source file
+#[derive(Clone, PartialEq)]
+struct Location {
+    file: Rc<str>,
+    number: usize,
+}
 
-                        binary_instructions |= arg2.to_string().parse::<u16>().unwrap();
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.number)
+    }
+}
 
-                        output_file
-                            .write(binary_instructions.to_be_bytes().as_slice())
-                            .unwrap();
-                    }
-                    "STORE" => {
-                        let (_, args) = line.split_once(char::is_whitespace).unwrap();
-                        let (arg1, arg2) = args.split_once(", ").unwrap();
-                        let mut binary_instructions = (OpCode::STORE as u16) << 12;
-
-                        match arg1 {
-                            "R0" => {
-                                binary_instructions |= (Register::R0 as u16) << 8;
-                            }
-                            "R1" => {
-                                binary_instructions |= (Register::R1 as u16) << 8;
-                            }
-                            "R2" => {
-                                binary_instructions |= (Register::R2 as u16) << 8;
-                            }
-                            "R3" => {
-                                binary_instructions |= (Register::R3 as u16) << 8;
-                            }
-                            _ => {
-                                panic!("Unknown register {arg1}")
-                            }
-                        }
+// A message for a line, and notes pointing at other lines (macro definitions)
+struct Diagnostic {
+    location: Location,
+    message: String,
+    notes: Vec<(Location, String)>,
+}
 
-                        binary_instructions |= arg2.to_string().parse::<u16>().unwrap();
+// A line with its comment and label taken off
+struct Statement<'a> {
+    line: &'a Line,
+    label: Option<&'a str>,
+    text: &'a str, // Empty for a line with only a label
+}
 
-                        output_file
-                            .write(binary_instructions.to_be_bytes().as_slice())
-                            .unwrap();
-                    }
-                    "ADD" => {
-                        let (_, args) = line.split_once(char::is_whitespace).unwrap();
-                        let (arg1, arg2) = args.split_once(", ").unwrap();
-                        let mut binary_instructions = (OpCode::ADD as u16) << 12;
-
-                        match arg1 {
-                            "R0" => {
-                                binary_instructions |= (Register::R0 as u16) << 8;
-                            }
-                            "R1" => {
-                                binary_instructions |= (Register::R1 as u16) << 8;
-                            }
-                            "R2" => {
-                                binary_instructions |= (Register::R2 as u16) << 8;
-                            }
-                            "R3" => {
-                                binary_instructions |= (Register::R3 as u16) << 8;
-                            }
-                            _ => {
-                                panic!("Unknown register {arg1}")
-                            }
-                        }
+// NOTE: Passes
+// Includes and macros are expanded first. The first pass then defines labels and lays every
+// statement out (start address and size), so .org and .space are evaluated there
+// and can only use symbols defined above them. The second pass encodes each
+// statement at its address with every label known.
+fn assemble(
+    filename: &str,
+    source: &str,
+    include_paths: Vec<PathBuf>,
+) -> Result<Vec<u16>, Vec<Diagnostic>> {
+    let mut preprocessor = Preprocessor {
+        include_paths,
+        ..Default::default()
+    };
+    let path = PathBuf::from(filename);
+    let canonical = fs::canonicalize(&path).unwrap_or(path);
+    preprocessor.included.push(canonical.clone());
+    preprocessor.including.push((canonical, Rc::from(filename)));
+    preprocessor.run(Rc::from(filename), source);
+    let Preprocessor {
+        lines,
+        mut errors,
+        files,
+        ..
+    } = preprocessor;
 
-                        match arg2 {
-                            "R0" => {
-                                binary_instructions |= Register::R0 as u16;
-                            }
-                            "R1" => {
-                                binary_instructions |= Register::R1 as u16;
-                            }
-                            "R2" => {
-                                binary_instructions |= Register::R2 as u16;
-                            }
-                            "R3" => {
-                                binary_instructions |= Register::R3 as u16;
-                            }
-                            _ => {
-                                panic!("Unknown register {arg1}")
-                            }
-                        }
+    let mut statements = Vec::new();
+    for line in &lines {
+        match split_label(&line.text) {
+            Ok((label, text)) => statements.push(Statement { line, label, text }),
+            Err(message) => errors.push(line.error(message)),
+        }
+    }
 
-                        output_file
-                            .write(binary_instructions.to_be_bytes().as_slice())
-                            .unwrap();
-                    }
-                    "SUB" => {
-                        let (_, args) = line.split_once(char::is_whitespace).unwrap();
-                        let (arg1, arg2) = args.split_once(", ").unwrap();
-                        let mut binary_instructions = (OpCode::SUB as u16) << 12;
-
-                        match arg1 {
-                            "R0" => {
-                                binary_instructions |= (Register::R0 as u16) << 8;
-                            }
-                            "R1" => {
-                                binary_instructions |= (Register::R1 as u16) << 8;
-                            }
-                            "R2" => {
-                                binary_instructions |= (Register::R2 as u16) << 8;
-                            }
-                            "R3" => {
-                                binary_instructions |= (Register::R3 as u16) << 8;
-                            }
-                            _ => {
-                                panic!("Unknown register {arg1}")
-                            }
-                        }
+    let mut symbols = Symbols::default();
+    let mut layout = Vec::new();
+    let mut location = 0;
+    for statement in &statements {
+        let laid_out = lay_out(statement, location, &mut symbols);
 
-                        match arg2 {
-                            "R0" => {
-                                binary_instructions |= Register::R0 as u16;
-                            }
-                            "R1" => {
-                                binary_instructions |= Register::R1 as u16;
-                            }
-                            "R2" => {
-                                binary_instructions |= Register::R2 as u16;
-                            }
-                            "R3" => {
-                                binary_instructions |= Register::R3 as u16;
-                            }
-                            _ => {
-                                panic!("Unknown register {arg1}")
-                            }
-                        }
+        // A label names where its statement starts, so one on an .org line is the
+        // new location
+        let start = laid_out.as_ref().map_or(location, |&(start, _)| start);
+        if let Some(label) = statement.label
+            && let Err(message) =
+                symbols.define(label, Symbol::Label(start), &statement.line.location)
+        {
+            errors.push(statement.line.error(message));
+        }
 
-                        output_file
-                            .write(binary_instructions.to_be_bytes().as_slice())
-                            .unwrap();
-                    }
-                    "JMP" => {
-                        let (_, arg) = line.split_once(char::is_whitespace).unwrap();
-                        let mut binary_instructions = (OpCode::JMP as u16) << 12;
-                        binary_instructions |= arg.to_string().parse::<u16>().unwrap();
-
-                        output_file
-                            .write(binary_instructions.to_be_bytes().as_slice())
-                            .unwrap();
+        match laid_out {
+            Ok((start, size)) => {
+                layout.push(Some((start, size)));
+                location = start + size;
+            }
+            Err(message) => {
+                layout.push(None);
+                errors.push(statement.line.error(message));
+            }
+        }
+    }
+
+    symbols.complete = true;
+    let mut image = Image::default();
+    for (statement, layout) in statements.iter().zip(layout) {
+        let Some((start, size)) = layout else {
+            continue;
+        };
+        image.location = start;
+        let result = encode(statement.text, size, &symbols)
+            .and_then(|words| image.emit(&words, &statement.line.location));
+        if let Err(message) = result {
+            errors.push(statement.line.error(message));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(image.words)
+    } else {
+        // Files in the order they were first included, lines in order within them
+        errors.sort_by_key(|error| {
+            let file = files.iter().position(|file| *file == error.location.file);
+            (file, error.location.number)
+        });
+        Err(errors)
+    }
+}
+
+// A source line with its comment taken off, after macro expansion
+#[derive(Clone)]
+struct Line {
+    location: Location, // For expanded lines, where the outermost macro was used
+    text: String,
+    expansion: Vec<(Location, String)>, // Macro body lines it came from, outermost first
+}
+
+impl Line {
+    fn error(&self, message: String) -> Diagnostic {
+        let mut notes: Vec<(Location, String)> = self
+            .expansion
+            .iter()
+            .map(|(location, name)| (location.clone(), format!("in macro {name}")))
+            .collect();
+        // A recursive macro repeats the same body line
+        notes.dedup();
+        Diagnostic {
+            location: self.location.clone(),
+            message,
+            notes,
+        }
+    }
+}
+
+// NOTE: Macros
+// .macro NAME a, b ... .endm defines a macro, used like an instruction: NAME 1, R0.
+// Parameters are replaced wherever they appear as a word outside literals, and
+// @name labels get a name of their own in every expansion so a macro can be used
+// more than once. Macros have to be defined before they're used. A macro can't use
+// itself, and macros using macros can only be nested MAX_MACRO_DEPTH deep.
+#[derive(Clone)]
+struct Macro {
+    location: Location, // Of the .macro line
+    parameters: Vec<String>,
+    body: Vec<(Location, String)>,
+}
+
+#[derive(Default)]
+struct Preprocessor {
+    macros: HashMap<String, Macro>,
+    expansions: usize,     // So far, numbers the local labels
+    reported: Vec<String>, // Macros already reported for using themselves or nesting too deep
+    include_paths: Vec<PathBuf>,
+    including: Vec<(PathBuf, Rc<str>)>, // Files being read (canonical path, name), outermost first
+    included: Vec<PathBuf>,             // Every file read, by canonical path
+    files: Vec<Rc<str>>,                // And by name, in order
+    lines: Vec<Line>,
+    errors: Vec<Diagnostic>,
+}
+
+impl Preprocessor {
+    // A .macro has to end in the file it started in
+    fn run(&mut self, file: Rc<str>, source: &str) {
+        self.files.push(file.clone());
+        // Name of the macro being defined, None when the .macro line was bad and the
+        // body is only skipped
+        let mut defining: Option<(Option<String>, Macro)> = None;
+
+        for (number, line) in source.lines().enumerate() {
+            // Ignore comments (; to the end of the line) and blank lines
+            let text = strip_comment(line).trim();
+            if text.is_empty() {
+                continue;
+            }
+            let line = Line {
+                location: Location {
+                    file: file.clone(),
+                    number: number + 1,
+                },
+                text: text.to_string(),
+                expansion: Vec::new(),
+            };
+            let (directive, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+
+            match (directive, &mut defining) {
+                (".endm", Some(_)) => {
+                    if let Some((Some(name), definition)) = defining.take() {
+                        self.macros.insert(name, definition);
                     }
-                    "MOV" => {
-                        let (_, args) = line.split_once(char::is_whitespace).unwrap();
-                        let (arg1, arg2) = args.split_once(", ").unwrap();
-                        let mut binary_instructions = (OpCode::MOV as u16) << 12;
-
-                        match arg1 {
-                            "R0" => {
-                                binary_instructions |= (Register::R0 as u16) << 8;
-                            }
-                            "R1" => {
-                                binary_instructions |= (Register::R1 as u16) << 8;
-                            }
-                            "R2" => {
-                                binary_instructions |= (Register::R2 as u16) << 8;
-                            }
-                            "R3" => {
-                                binary_instructions |= (Register::R3 as u16) << 8;
-                            }
-                            _ => {
-                                panic!("Unknown register {arg1}")
-                            }
+                }
+                (".macro", Some(_)) => self
+                    .errors
+                    .push(line.error(String::from("Macros can't be defined inside a macro"))),
+                (".include", Some(_)) => self
+                    .errors
+                    .push(line.error(String::from("Files can't be included inside a macro"))),
+                (_, Some((_, definition))) => definition.body.push((line.location, line.text)),
+                (".macro", None) => {
+                    let name = match self.define(args) {
+                        Ok((name, parameters)) => Some((name, parameters)),
+                        Err(message) => {
+                            self.errors.push(line.error(message));
+                            None
                         }
+                    };
+                    let (name, parameters) = name.unzip();
+                    defining = Some((
+                        name,
+                        Macro {
+                            location: line.location.clone(),
+                            parameters: parameters.unwrap_or_default(),
+                            body: Vec::new(),
+                        },
+                    ));
+                }
+                (".endm", None) => self
+                    .errors
+                    .push(line.error(String::from(".endm without .macro"))),
+                (".include", None) => self.include(args, &line),
+                _ => self.expand(line),
+            }
+        }
 
-                        match arg2 {
-                            "R0" => {
-                                binary_instructions |= Register::R0 as u16;
-                            }
-                            "R1" => {
-                                binary_instructions |= Register::R1 as u16;
-                            }
-                            "R2" => {
-                                binary_instructions |= Register::R2 as u16;
-                            }
-                            "R3" => {
-                                binary_instructions |= Register::R3 as u16;
-                            }
-                            _ => {
-                                panic!("Unknown register {arg1}")
-                            }
-                        }
+        if let Some((_, definition)) = defining {
+            self.errors.push(Diagnostic {
+                location: definition.location,
+                message: String::from(".macro without .endm"),
+                notes: Vec::new(),
+            });
+        }
+    }
 
-                        output_file
-                            .write(binary_instructions.to_be_bytes().as_slice())
-                            .unwrap();
-                    }
-                    _ => {
-                        panic!("Unknown instruction {line}")
-                    }
+    // A file is looked for next to the one including it, then in each include path.
+    // Files already read are skipped, so libraries can include what they need.
+    fn include(&mut self, args: &str, line: &Line) {
+        let Some(name) = args
+            .strip_prefix('"')
+            .and_then(|rest| rest.strip_suffix('"'))
+            .filter(|name| !name.is_empty())
+        else {
+            self.errors
+                .push(line.error(String::from(".include takes a quoted file name")));
+            return;
+        };
+
+        let directory = Path::new(&*line.location.file)
+            .parent()
+            .unwrap_or(Path::new(""));
+        let directories: Vec<&Path> = std::iter::once(directory)
+            .chain(self.include_paths.iter().map(PathBuf::as_path))
+            .collect();
+        let Some(path) = directories
+            .iter()
+            .map(|directory| directory.join(name))
+            .find(|path| path.is_file())
+        else {
+            let searched: Vec<String> = directories
+                .iter()
+                .map(|directory| match directory.to_str() {
+                    Some("") => String::from("."),
+                    _ => directory.display().to_string(),
+                })
+                .collect();
+            self.errors.push(line.error(format!(
+                "Can't find {name} (looked in {})",
+                searched.join(", ")
+            )));
+            return;
+        };
+
+        let canonical = fs::canonicalize(&path).unwrap_or(path.clone());
+        let file: Rc<str> = Rc::from(path.to_string_lossy());
+        if let Some(start) = self
+            .including
+            .iter()
+            .position(|(other, _)| *other == canonical)
+        {
+            let cycle: Vec<&str> = self.including[start..]
+                .iter()
+                .map(|(_, file)| &**file)
+                .chain(std::iter::once(&*file))
+                .collect();
+            self.errors
+                .push(line.error(format!("Include cycle: {}", cycle.join(" -> "))));
+            return;
+        }
+        if self.included.contains(&canonical) {
+            return;
+        }
+
+        match fs::read_to_string(&path) {
+            Ok(source) => {
+                self.included.push(canonical.clone());
+                self.including.push((canonical, file.clone()));
+                self.run(file, &source);
+                self.including.pop();
+            }
+            Err(e) => self.errors.push(line.error(format!("{name}: {e}"))),
+        }
+    }
+
+    // Name and parameters from a .macro line
+    fn define(&self, args: &str) -> Result<(String, Vec<String>), String> {
+        let (name, parameters) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        if name.is_empty() {
+            return Err(String::from(".macro takes a name and parameters"));
+        }
+        check_name(name)?;
+        if MNEMONICS.contains(&name) {
+            return Err(format!("{name} is an instruction"));
+        }
+        if let Some(other) = self.macros.get(name) {
+            return Err(format!(
+                "Macro {name} is already defined at {}",
+                other.location
+            ));
+        }
+
+        let parameters: Vec<String> = split_operands(parameters)
+            .into_iter()
+            .map(String::from)
+            .collect();
+        for (i, parameter) in parameters.iter().enumerate() {
+            check_name(parameter)?;
+            if parameters[..i].contains(parameter) {
+                return Err(format!("Parameter {parameter} is listed twice"));
+            }
+        }
+
+        Ok((name.to_string(), parameters))
+    }
+
+    // Lines that use a macro are replaced by its body, recursively
+    fn expand(&mut self, line: Line) {
+        let (label, text) = split_label(&line.text).unwrap_or((None, &line.text));
+        let (name, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let Some(definition) = self.macros.get(name).cloned() else {
+            self.lines.push(line);
+            return;
+        };
+
+        // Without conditionals a macro using itself never ends, and every use
+        // multiplies the expansion. The notes trace the chain of uses back to it.
+        let message = if line.expansion.iter().any(|(_, outer)| outer == name) {
+            Some(format!("Macro {name} uses itself"))
+        } else if line.expansion.len() >= MAX_MACRO_DEPTH {
+            Some(format!(
+                "Macro {name} is nested more than {MAX_MACRO_DEPTH} macros deep"
+            ))
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            if !self.reported.iter().any(|other| other == name) {
+                self.reported.push(name.to_string());
+                let mut error = line.error(message);
+                error
+                    .notes
+                    .push((definition.location, format!("macro {name} defined here")));
+                self.errors.push(error);
+            }
+            return;
+        }
+
+        let args = split_operands(args);
+        let count = definition.parameters.len();
+        let error = if args.len() != count {
+            let plural = if count == 1 { "" } else { "s" };
+            Some(format!(
+                "{name} takes {count} argument{plural}, got {}",
+                args.len()
+            ))
+        } else {
+            None
+        };
+        if let Some(message) = error {
+            let mut error = line.error(message);
+            error
+                .notes
+                .push((definition.location, format!("macro {name} defined here")));
+            self.errors.push(error);
+            return;
+        }
+
+        self.expansions += 1;
+        if let Some(label) = label {
+            self.lines.push(Line {
+                text: format!("{label}:"),
+                ..line.clone()
+            });
+        }
+        for (location, body) in &definition.body {
+            let mut expansion = line.expansion.clone();
+            expansion.push((location.clone(), name.to_string()));
+            self.expand(Line {
+                location: line.location.clone(),
+                text: substitute(body, &definition.parameters, &args, self.expansions),
+                expansion,
+            });
+        }
+    }
+}
+
+// One macro body line with the arguments and this expansion's local labels filled in
+fn substitute(text: &str, parameters: &[String], args: &[&str], expansion: usize) -> String {
+    let outside: Vec<usize> = unquoted(text).map(|(i, _)| i).collect();
+    let mut result = String::new();
+    let mut rest = text.char_indices().peekable();
+
+    while let Some((start, c)) = rest.next() {
+        let word_start = c.is_ascii_alphanumeric() || c == '_' || c == '@';
+        if !word_start || !outside.contains(&start) {
+            result.push(c);
+            continue;
+        }
+
+        // Whole words only, numbers such as 0x1F are copied as they are
+        let mut end = start + 1;
+        while let Some(&(i, c)) = rest.peek()
+            && (c.is_ascii_alphanumeric() || c == '_')
+        {
+            end = i + 1;
+            rest.next();
+        }
+        let word = &text[start..end];
+        match parameters.iter().position(|parameter| parameter == word) {
+            Some(i) => result.push_str(args[i]),
+            None if let Some(local) = word.strip_prefix('@') => {
+                result.push_str(&format!("{local}__{expansion}"))
+            }
+            None => result.push_str(word),
+        }
+    }
+    result
+}
+
+// "NAME:" in front of a statement defines a label
+fn split_label(line: &str) -> Result<(Option<&str>, &str), String> {
+    match line.split_once(':') {
+        Some((label, text)) if !label.contains(char::is_whitespace) && !label.contains('\'') => {
+            check_name(label)?;
+            Ok((Some(label), text.trim()))
+        }
+        _ => Ok((None, line)),
+    }
+}
+
+// Start address and number of words. .equ is defined here so later .org and .space
+// lines can use it
+fn lay_out(
+    statement: &Statement,
+    location: usize,
+    symbols: &mut Symbols,
+) -> Result<(usize, usize), String> {
+    let Some(directive) = statement.text.strip_prefix('.') else {
+        let size = if statement.text.is_empty() { 0 } else { 1 };
+        return Ok((location, size));
+    };
+    let (directive, args) = directive
+        .split_once(char::is_whitespace)
+        .unwrap_or((directive, ""));
+    let args = split_operands(args);
+
+    match (directive, &args[..]) {
+        ("equ", [name, expression]) => {
+            check_name(name)?;
+            let constant = Symbol::Constant(expression.to_string());
+            symbols.define(name, constant, &statement.line.location)?;
+            Ok((location, 0))
+        }
+        ("equ", _) => Err(String::from(".equ takes a name and a value")),
+        ("word" | "byte", _) => Ok((location, args.len())),
+        // Malformed strings take no space here, the second pass reports them
+        ("string", _) => Ok((
+            location,
+            args.iter()
+                .map(|arg| parse_string(arg).map_or(0, |words| words.len() + 1))
+                .sum(),
+        )),
+        ("space", [count]) => Ok((location, parse_address(count, symbols)?)),
+        ("space", _) => Err(String::from(".space takes a number of words")),
+        ("org", [address]) => Ok((parse_address(address, symbols)?, 0)),
+        ("org", _) => Err(String::from(".org takes an address")),
+        _ => Ok((location, 0)),
+    }
+}
+
+// Words for a statement laid out as size words
+fn encode(text: &str, size: usize, symbols: &Symbols) -> Result<Vec<u16>, String> {
+    match text.strip_prefix('.') {
+        Some(directive) => encode_directive(directive, size, symbols),
+        None if text.is_empty() => Ok(Vec::new()),
+        None => Ok(vec![assemble_instruction(text, symbols)?]),
+    }
+}
+
+// NOTE: Directives
+// .word and .byte place one value per word (memory is word addressed), .string one
+// character per word and a terminating zero, .space N zero words, .org N moves the
+// location counter and .equ NAME, VALUE defines a constant
+fn encode_directive(line: &str, size: usize, symbols: &Symbols) -> Result<Vec<u16>, String> {
+    let (directive, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = split_operands(args);
+
+    match (directive, &args[..]) {
+        ("word", [_, ..]) => args
+            .iter()
+            .map(|arg| parse_sized(arg, WORD_BITS, symbols))
+            .collect(),
+        ("byte", [_, ..]) => args
+            .iter()
+            .map(|arg| parse_sized(arg, 8, symbols))
+            .collect(),
+        ("string", [_, ..]) => {
+            let mut words = Vec::new();
+            for arg in args {
+                words.extend(parse_string(arg)?);
+                words.push(0);
+            }
+            Ok(words)
+        }
+        ("space", _) => Ok(vec![0; size]),
+        ("org", _) => Ok(Vec::new()),
+        // Evaluated once here so a bad definition is reported even if it's never used
+        ("equ", [name, _]) => symbols.value(name, &mut Vec::new()).map(|_| Vec::new()),
+        ("word" | "byte", _) => Err(format!(".{directive} takes one or more values")),
+        ("string", _) => Err(String::from(".string takes one or more quoted strings")),
+        _ => Err(format!("Unknown directive .{directive}")),
+    }
+}
+
+fn assemble_instruction(line: &str, symbols: &Symbols) -> Result<u16, String> {
+    // HALT does not contain any argument
+    let (instruction, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = split_operands(args);
+
+    let (opcode, register, operand) = match (instruction, &args[..]) {
+        ("HALT", []) => (OpCode::HALT, 0, 0),
+        ("RETI", []) => (OpCode::RETI, 0, 0),
+        ("RET", []) => (OpCode::RET, 0, 0),
+        ("JMP", [target]) => (OpCode::JMP, 0, parse_operand(target, symbols)?),
+        ("CALL", [target]) => (OpCode::CALL, 0, parse_operand(target, symbols)?),
+        ("ADD", [destination, source]) => (
+            OpCode::ADD,
+            parse_register(destination)?,
+            parse_register(source)? as u16,
+        ),
+        ("SUB", [destination, source]) => (
+            OpCode::SUB,
+            parse_register(destination)?,
+            parse_register(source)? as u16,
+        ),
+        ("MOV", [destination, source]) => (
+            OpCode::MOV,
+            parse_register(destination)?,
+            parse_register(source)? as u16,
+        ),
+        ("LVAL", [register, value]) => (
+            OpCode::LVAL,
+            parse_register(register)?,
+            parse_operand(value, symbols)?,
+        ),
+        ("LOAD", [register, address]) => (
+            OpCode::LOAD,
+            parse_register(register)?,
+            parse_operand(address, symbols)?,
+        ),
+        ("STORE", [register, address]) => (
+            OpCode::STORE,
+            parse_register(register)?,
+            parse_operand(address, symbols)?,
+        ),
+        ("JNZ", [register, target]) => (
+            OpCode::JNZ,
+            parse_register(register)?,
+            parse_operand(target, symbols)?,
+        ),
+        _ => {
+            let usage = match instruction {
+                "HALT" | "RETI" | "RET" => "no operands",
+                "JMP" | "CALL" => "a target address",
+                "ADD" | "SUB" | "MOV" => "two registers",
+                "LVAL" => "a register and a value",
+                "LOAD" | "STORE" => "a register and an address",
+                "JNZ" => "a register and a target address",
+                _ => return Err(format!("Unknown instruction {instruction}")),
+            };
+            return Err(format!("{instruction} takes {usage}"));
+        }
+    };
+
+    Ok((opcode as u16) << 12 | (register as u16) << 8 | operand)
+}
+
+// Operands are expressions. Negative values are stored as two's complement of the
+// field, anything that doesn't fit the 8-bit operand field is an error rather than
+// spilling into the register and opcode bits.
+fn parse_operand(arg: &str, symbols: &Symbols) -> Result<u16, String> {
+    parse_sized(arg, OPERAND_BITS, symbols)
+}
+
+fn parse_sized(arg: &str, bits: u32, symbols: &Symbols) -> Result<u16, String> {
+    syntax::fit(arg, symbols.evaluate(arg)?, bits)
+}
+
+// Sizes and addresses for .space and .org
+fn parse_address(arg: &str, symbols: &Symbols) -> Result<usize, String> {
+    let value = symbols.evaluate(arg)?;
+    if !(0..=MEMORY_WORDS as i64).contains(&value) {
+        return Err(format!(
+            "{} is outside memory (0 to {MEMORY_WORDS})",
+            shown(arg, value)
+        ));
+    }
+    Ok(value as usize)
+}
+
+// NOTE: Symbols
+// Labels are addresses, .equ constants keep their expression and are evaluated where
+// they're used, so they can refer to labels further down
+enum Symbol {
+    Label(usize),
+    Constant(String),
+}
+
+#[derive(Default)]
+struct Symbols {
+    table: HashMap<String, (Symbol, Location)>, // With the line defining it
+    complete: bool,                             // Every label is defined (second pass)
+}
+
+impl Symbols {
+    fn define(&mut self, name: &str, symbol: Symbol, line: &Location) -> Result<(), String> {
+        if let Some((_, other)) = self.table.get(name) {
+            return Err(format!("{name} is already defined at {other}"));
+        }
+        self.table.insert(name.to_string(), (symbol, line.clone()));
+        Ok(())
+    }
+
+    fn evaluate(&self, expression: &str) -> Result<i64, String> {
+        Expression::parse(expression, self, &mut Vec::new())
+    }
+
+    // resolving holds the constants being evaluated, to catch circular definitions
+    fn value(&self, name: &str, resolving: &mut Vec<String>) -> Result<i64, String> {
+        match self.table.get(name) {
+            Some((Symbol::Label(address), _)) => Ok(*address as i64),
+            Some((Symbol::Constant(expression), line)) => {
+                if resolving.iter().any(|other| other == name) {
+                    return Err(format!(
+                        "Circular definition {} -> {name}",
+                        resolving.join(" -> ")
+                    ));
+                }
+                resolving.push(name.to_string());
+                let value = Expression::parse(expression, self, resolving);
+                resolving.pop();
+                // Errors deeper down are reported against the constant that was used
+                match value {
+                    Err(e) if resolving.is_empty() => Err(format!("{e} (in {name} at {line})")),
+                    value => value,
                 }
             }
+            None if self.complete => Err(format!("Unknown symbol {name}")),
+            None => Err(format!("{name} isn't defined above this line")),
+        }
+    }
+}
+
+// Names start with a letter or _, registers and functions are reserved
+fn check_name(name: &str) -> Result<(), String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("Invalid name {name}"));
+    }
+    if parse_register(name).is_ok() || FUNCTIONS.contains(&name) {
+        return Err(format!("{name} is reserved"));
+    }
+    Ok(())
+}
+
+// NOTE: Expressions
+// + - * / % on integers, unary minus, parentheses and HIGH(x)/LOW(x) for the upper
+// and lower byte of a word. Every intermediate result has to fit in 16 bits (-32768
+// to 65535), so overflow is reported where it happens instead of wrapping.
+const FUNCTIONS: [&str; 2] = ["HIGH", "LOW"];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Token<'a> {
+    Number(i64),
+    Name(&'a str),
+    Operator(char),
+}
+
+struct Expression<'a, 'b> {
+    text: &'a str,
+    tokens: Vec<(usize, Token<'a>)>, // With their offset in text
+    position: usize,
+    symbols: &'b Symbols,
+    resolving: &'b mut Vec<String>,
+}
+
+impl<'a, 'b> Expression<'a, 'b> {
+    fn parse(
+        text: &'a str,
+        symbols: &'b Symbols,
+        resolving: &'b mut Vec<String>,
+    ) -> Result<i64, String> {
+        let mut expression = Expression {
+            text,
+            tokens: tokenize(text)?,
+            position: 0,
+            symbols,
+            resolving,
+        };
+        if expression.tokens.is_empty() {
+            return Err(String::from("Expected a value"));
+        }
+
+        let value = expression.sum()?;
+        match expression.tokens.get(expression.position) {
+            Some(&(offset, _)) => Err(format!("Unexpected {} in {text}", &text[offset..])),
+            None => Ok(value),
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.text.len(), |&(offset, _)| offset)
+    }
+
+    fn next_operator(&mut self, operators: &[char]) -> Option<char> {
+        match self.tokens.get(self.position) {
+            Some(&(_, Token::Operator(operator))) if operators.contains(&operator) => {
+                self.position += 1;
+                Some(operator)
+            }
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, operator: char) -> Result<(), String> {
+        self.next_operator(&[operator])
+            .map(|_| ())
+            .ok_or_else(|| format!("Expected {operator} in {}", self.text))
+    }
+
+    // The text from start to the current token, for diagnostics
+    fn checked(&self, value: Option<i64>, start: usize) -> Result<i64, String> {
+        let part = self.text[start..self.offset()].trim();
+        match value {
+            Some(value) if (-32768..=65535).contains(&value) => Ok(value),
+            Some(value) => Err(format!(
+                "Overflow: {part} = {value} doesn't fit in {WORD_BITS} bits"
+            )),
+            None => Err(format!("Division by zero in {part}")),
         }
-        Err(e) => {
-            panic!("{e}");
+    }
+
+    fn sum(&mut self) -> Result<i64, String> {
+        let start = self.offset();
+        let mut value = self.product()?;
+        while let Some(operator) = self.next_operator(&['+', '-']) {
+            let right = self.product()?;
+            let result = match operator {
+                '+' => value.checked_add(right),
+                _ => value.checked_sub(right),
+            };
+            value = self.checked(result, start)?;
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<i64, String> {
+        let start = self.offset();
+        let mut value = self.unary()?;
+        while let Some(operator) = self.next_operator(&['*', '/', '%']) {
+            let right = self.unary()?;
+            let result = match operator {
+                '*' => value.checked_mul(right),
+                '/' => value.checked_div(right),
+                _ => value.checked_rem(right),
+            };
+            value = self.checked(result, start)?;
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        let start = self.offset();
+        if self.next_operator(&['-']).is_some() {
+            let value = self.unary()?;
+            return self.checked(value.checked_neg(), start);
         }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<i64, String> {
+        let Some(&(offset, token)) = self.tokens.get(self.position) else {
+            return Err(format!("Expected a value at the end of {}", self.text));
+        };
+        self.position += 1;
+
+        match token {
+            Token::Number(value) => Ok(value),
+            Token::Operator('(') => {
+                let value = self.sum()?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Token::Name(name) if FUNCTIONS.contains(&name) => {
+                self.expect('(')?;
+                let value = self.sum()? as u16;
+                self.expect(')')?;
+                Ok(match name {
+                    "HIGH" => value >> 8,
+                    _ => value & 0xFF,
+                } as i64)
+            }
+            Token::Name(name) => self.symbols.value(name, self.resolving),
+            Token::Operator(_) => Err(format!(
+                "Unexpected {} in {}",
+                &self.text[offset..],
+                self.text
+            )),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token<'_>)>, String> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+
+    while let Some(c) = text[start..].chars().next() {
+        let rest = &text[start..];
+        let word = || {
+            rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len())
+        };
+
+        let (token, length) = match c {
+            _ if c.is_whitespace() => (None, c.len_utf8()),
+            '0'..='9' => {
+                let length = word();
+                (Some(Token::Number(parse_literal(&rest[..length])?)), length)
+            }
+            'A'..='Z' | 'a'..='z' | '_' => {
+                let length = word();
+                (Some(Token::Name(&rest[..length])), length)
+            }
+            // Up to the closing quote that isn't escaped
+            '\'' => {
+                let mut escaped = false;
+                let length = rest
+                    .char_indices()
+                    .skip(1)
+                    .find(|&(_, c)| {
+                        let closing = !escaped && c == '\'';
+                        escaped = !escaped && c == '\\';
+                        closing
+                    })
+                    .map_or(rest.len(), |(i, _)| i + 1);
+                (Some(Token::Number(parse_literal(&rest[..length])?)), length)
+            }
+            '+' | '-' | '*' | '/' | '%' | '(' | ')' => (Some(Token::Operator(c)), 1),
+            _ => return Err(format!("Unexpected {c} in {text}")),
+        };
+
+        if let Some(token) = token {
+            tokens.push((start, token));
+        }
+        start += length;
+    }
+    Ok(tokens)
+}
+
+// Characters of a "..." literal, without the terminating zero
+fn parse_string(arg: &str) -> Result<Vec<u16>, String> {
+    let invalid = || format!("Invalid string {arg}");
+    let mut chars = arg
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(invalid)?
+        .chars();
+
+    let mut words = Vec::new();
+    while !chars.as_str().is_empty() {
+        if chars.as_str().starts_with('"') {
+            return Err(invalid());
+        }
+        let c = literal_char(&mut chars).ok_or_else(invalid)?;
+        let word = u16::try_from(c as u32)
+            .map_err(|_| format!("{c} in {arg} doesn't fit in {WORD_BITS} bits"))?;
+        words.push(word);
+    }
+    Ok(words)
+}
+
+// Everything before a ; that isn't inside a literal
+fn strip_comment(line: &str) -> &str {
+    match unquoted(line).find(|&(_, c)| c == ';') {
+        Some((i, _)) => &line[..i],
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assembled(source: &str) -> Vec<u16> {
+        match assemble("test.asm", source, Vec::new()) {
+            Ok(words) => words,
+            Err(errors) => panic!("{}", errors[0].message),
+        }
+    }
+
+    // "line: message" for every error, notes left out
+    fn errors(source: &str) -> Vec<String> {
+        assemble("test.asm", source, Vec::new())
+            .err()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|error| format!("{}: {}", error.location.number, error.message))
+            .collect()
+    }
+
+    #[test]
+    fn data_directives_place_words() {
+        let source = "LOAD R0, 4\nHALT\n.space 2\n.word 1000, 0x1234\n.byte -1, 'A'\n\
+                      .org 10\n.string \"a\\n\", \"\"";
+        let mut expected = vec![0x2004, 0x0000, 0, 0, 1000, 0x1234, 0x00ff, 0x0041];
+        expected.extend([0, 0, 0x61, 0x0a, 0, 0]);
+        assert_eq!(assembled(source), expected);
+    }
+
+    #[test]
+    fn data_directives_report_bad_values_and_placement() {
+        let source = ".word 70000\n.byte 256\n.string \"ab\n.space 70\n.word\n.frob 3";
+        assert_eq!(
+            errors(source),
+            [
+                "1: 70000 doesn't fit in 16 bits (-32768 to 65535)",
+                "2: 256 doesn't fit in 8 bits (-128 to 255)",
+                "3: Invalid string \"ab",
+                "4: 70 is outside memory (0 to 64)",
+                "5: .word takes one or more values",
+                "6: Unknown directive .frob",
+            ]
+        );
+        assert_eq!(
+            errors("HALT\n.org 0\n.word 5"),
+            ["3: Overlaps address 0, already assembled at test.asm:1"]
+        );
+        assert_eq!(
+            errors(".org 63\n.word 1, 2"),
+            ["2: Doesn't fit in memory, 64 words from 0 to 63"]
+        );
+    }
+
+    #[test]
+    fn constants_and_expressions() {
+        let source = ".equ LENGTH, END-TABLE\n.equ WORD, 0x1234\nLOAD R0, TABLE+2\n\
+                      LVAL R1, LENGTH\nLVAL R2, (END-TABLE)/2\nLVAL R3, HIGH(WORD)\n\
+                      LVAL R3, LOW(WORD)\nHALT\nTABLE: .org 10\n.word 1, 2, 3, 10 % 4 * 3\nEND:";
+        let mut expected = vec![0x200c, 0x1104, 0x1202, 0x1312, 0x1334, 0x0000];
+        expected.extend([0; 4]);
+        expected.extend([1, 2, 3, 6]);
+        assert_eq!(assembled(source), expected);
+    }
+
+    #[test]
+    fn expression_errors() {
+        let source = ".equ BIG, 0x8000\n.equ X, BIG*2\n.equ Z, 1/0\nLVAL R0, HIGH 3\n\
+                      LVAL R0, UNKNOWN\n.equ A, B\n.equ B, A\nLATER: .org LATE2\nLATE2: HALT\n\
+                      .equ BIG, 2\nLVAL R0, BIG";
+        assert_eq!(
+            errors(source),
+            [
+                "2: Overflow: BIG*2 = 65536 doesn't fit in 16 bits (in X at test.asm:2)",
+                "3: Division by zero in 1/0 (in Z at test.asm:3)",
+                "4: Expected ( in HIGH 3",
+                "5: Unknown symbol UNKNOWN",
+                "6: Circular definition A -> B -> A (in A at test.asm:6)",
+                "7: Circular definition B -> A -> B (in B at test.asm:7)",
+                "8: LATE2 isn't defined above this line",
+                "10: BIG is already defined at test.asm:1",
+                "11: BIG = 32768 doesn't fit in 8 bits (-128 to 255)",
+            ]
+        );
+    }
+
+    // Every line of the first error, as the assembler prints them
+    fn first_error(source: &str) -> Vec<String> {
+        let error = assemble("test.asm", source, Vec::new())
+            .err()
+            .unwrap()
+            .remove(0);
+        let mut lines = vec![format!("{}: {}", error.location, error.message)];
+        lines.extend(
+            error
+                .notes
+                .iter()
+                .map(|(location, note)| format!("{location}: {note}")),
+        );
+        lines
+    }
+
+    #[test]
+    fn macros_get_local_labels_per_expansion() {
+        let source = ".macro COUNTDOWN reg, n\n  LVAL reg, n\n@loop: SUB reg, R3\n\
+                      JNZ reg, @loop\n.endm\nLVAL R3, 1\nCOUNTDOWN R0, 3\nCOUNTDOWN R1, 2\nHALT";
+        assert_eq!(
+            assembled(source),
+            [
+                0x1301, 0x1003, 0x5003, 0x9002, 0x1102, 0x5103, 0x9105, 0x0000
+            ]
+        );
+    }
+
+    #[test]
+    fn macro_errors_point_at_use_and_definition() {
+        let source = ".macro BAD reg\n    LVAL reg, 300\n.endm\n.macro OUTER x\n    BAD x\n.endm\n\
+                      OUTER R0";
+        assert_eq!(
+            first_error(source),
+            [
+                "test.asm:7: 300 doesn't fit in 8 bits (-128 to 255)",
+                "test.asm:5: in macro OUTER",
+                "test.asm:2: in macro BAD",
+            ]
+        );
+        assert_eq!(
+            first_error(".macro BAD reg\n    LVAL reg, 1\n.endm\nBAD R1, R2"),
+            [
+                "test.asm:4: BAD takes 1 argument, got 2",
+                "test.asm:1: macro BAD defined here",
+            ]
+        );
+    }
+
+    #[test]
+    fn recursive_macros_are_rejected() {
+        let source = ".macro PING\n  PONG\n.endm\n.macro PONG\n  PING\n.endm\nPONG\nPONG";
+        assert_eq!(
+            first_error(source),
+            [
+                "test.asm:7: Macro PONG uses itself",
+                "test.asm:5: in macro PONG",
+                "test.asm:2: in macro PING",
+                "test.asm:4: macro PONG defined here",
+            ]
+        );
+        // Reported once, not for every use
+        assert_eq!(errors(source).len(), 1);
+    }
+
+    #[test]
+    fn macro_nesting_is_limited() {
+        let mut source = String::from(".macro M0\n  HALT\n.endm\n");
+        for level in 1..=MAX_MACRO_DEPTH {
+            source += &format!(".macro M{level}\n  M{}\n.endm\n", level - 1);
+        }
+        // M15 nests 16 macros deep, M16 one more
+        assert_eq!(assembled(&(source.clone() + "M15")), [0x0000]);
+        let chain = first_error(&(source + "M16"));
+        assert_eq!(
+            chain[0],
+            "test.asm:52: Macro M0 is nested more than 16 macros deep"
+        );
+        assert_eq!(chain.len(), 1 + MAX_MACRO_DEPTH + 1);
+        assert_eq!(chain[1], "test.asm:50: in macro M16");
+        assert_eq!(chain.last().unwrap(), "test.asm:1: macro M0 defined here");
+    }
+
+    // A fresh directory holding the given files
+    fn directory(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("assembler-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        for (file, source) in files {
+            let path = directory.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        directory
+    }
+
+    fn assemble_file(
+        directory: &Path,
+        include_paths: Vec<PathBuf>,
+    ) -> Result<Vec<u16>, Vec<String>> {
+        let main = directory.join("main.asm");
+        let source = fs::read_to_string(&main).unwrap();
+        assemble(main.to_str().unwrap(), &source, include_paths).map_err(|errors| {
+            errors
+                .into_iter()
+                .map(|error| format!("{}: {}", error.location, error.message))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn includes_are_found_next_to_the_file_then_in_include_paths() {
+        let directory = directory(
+            "paths",
+            &[
+                (
+                    "main.asm",
+                    ".include \"double.asm\"\n.include \"util.asm\"\nLVAL R0, 3\nDOUBLE R0\nHALT",
+                ),
+                ("util.asm", ".include \"double.asm\"\n"),
+                ("lib/double.asm", ".macro DOUBLE reg\n  ADD reg, reg\n.endm"),
+            ],
+        );
+        let result = assemble_file(&directory, vec![directory.join("lib")]);
+        let missing = assemble_file(&directory, Vec::new());
+        fs::remove_dir_all(&directory).unwrap();
+
+        // double.asm is read once even though util.asm includes it again
+        assert_eq!(result, Ok(vec![0x1003, 0x4000, 0x0000]));
+        let main = directory.join("main.asm").display().to_string();
+        assert_eq!(
+            missing.unwrap_err()[0],
+            format!(
+                "{main}:1: Can't find double.asm (looked in {})",
+                directory.display()
+            )
+        );
+    }
+
+    #[test]
+    fn include_cycles_are_reported() {
+        let directory = directory(
+            "cycle",
+            &[
+                ("main.asm", ".include \"a.asm\"\nHALT"),
+                ("a.asm", "LVAL R0, 1\n.include \"b.asm\""),
+                ("b.asm", ".include \"a.asm\""),
+            ],
+        );
+        let result = assemble_file(&directory, Vec::new());
+        fs::remove_dir_all(&directory).unwrap();
+
+        let file = |name: &str| directory.join(name).display().to_string();
+        assert_eq!(
+            result,
+            Err(vec![format!(
+                "{}:1: Include cycle: {} -> {} -> {}",
+                file("b.asm"),
+                file("a.asm"),
+                file("b.asm"),
+                file("a.asm")
+            )])
+        );
     }
 }
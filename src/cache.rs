@@ -0,0 +1,318 @@
+// NOTE: Cache configuration
+#[derive(Clone, Copy)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random,
+}
+
+#[derive(Clone, Copy)]
+pub enum WritePolicy {
+    WriteBack,    // Write-allocate, memory updated on eviction of dirty lines
+    WriteThrough, // No-write-allocate, memory updated on every store
+}
+
+#[derive(Clone, Copy)]
+pub struct CacheConfig {
+    pub lines: usize,     // Total number of lines
+    pub line_size: usize, // Words per line
+    pub ways: usize,      // Associativity, lines per set
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+    pub miss_penalty: u64, // Extra cycles per miss
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            lines: 8,
+            line_size: 4,
+            ways: 2,
+            replacement: Replacement::Lru,
+            write_policy: WritePolicy::WriteBack,
+            miss_penalty: 10,
+        }
+    }
+}
+
+// More lines than the 64 words of memory are never used, wider lines overflow the Cache pane
+const MAX_LINES: usize = 64;
+const MAX_LINE_SIZE: usize = 16;
+
+impl CacheConfig {
+    // Parses comma separated key=value pairs, e.g. lines=8,ways=2,replace=fifo,write=through
+    pub fn parse(spec: &str) -> Result<CacheConfig, String> {
+        let mut config = CacheConfig::default();
+
+        for pair in spec.split(',').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value in cache spec, got {pair}"))?;
+            let number = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid number for {key}: {value}"))
+            };
+
+            match key {
+                "lines" => config.lines = number()?,
+                "line" => config.line_size = number()?,
+                "ways" => config.ways = number()?,
+                "penalty" => config.miss_penalty = number()? as u64,
+                "replace" => {
+                    config.replacement = match value {
+                        "lru" => Replacement::Lru,
+                        "fifo" => Replacement::Fifo,
+                        "random" => Replacement::Random,
+                        _ => return Err(format!("Unknown replacement policy {value}")),
+                    }
+                }
+                "write" => {
+                    config.write_policy = match value {
+                        "back" => WritePolicy::WriteBack,
+                        "through" => WritePolicy::WriteThrough,
+                        _ => return Err(format!("Unknown write policy {value}")),
+                    }
+                }
+                _ => return Err(format!("Unknown cache option {key}")),
+            }
+        }
+
+        if config.lines == 0 || config.line_size == 0 || config.ways == 0 {
+            return Err(String::from(
+                "Cache lines, line size and ways must be non-zero",
+            ));
+        }
+        if config.lines > MAX_LINES {
+            return Err(format!("Cache lines must be at most {MAX_LINES}"));
+        }
+        if config.line_size > MAX_LINE_SIZE {
+            return Err(format!("Cache line size must be at most {MAX_LINE_SIZE}"));
+        }
+        if config.ways > config.lines {
+            return Err(String::from(
+                "Cache ways must be at most the number of lines",
+            ));
+        }
+        if config.lines % config.ways != 0 {
+            return Err(String::from("Cache lines must be a multiple of ways"));
+        }
+
+        Ok(config)
+    }
+
+    pub fn sets(&self) -> usize {
+        self.lines / self.ways
+    }
+
+    pub fn describe(&self) -> String {
+        let replacement = match self.replacement {
+            Replacement::Lru => "LRU",
+            Replacement::Fifo => "FIFO",
+            Replacement::Random => "random",
+        };
+        let write_policy = match self.write_policy {
+            WritePolicy::WriteBack => "write-back",
+            WritePolicy::WriteThrough => "write-through",
+        };
+
+        format!(
+            "{} lines x {} words, {}-way, {}, {}",
+            self.lines, self.line_size, self.ways, replacement, write_policy
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct CacheLine {
+    pub valid: bool,
+    pub dirty: bool,
+    pub tag: usize,
+    pub data: Vec<u16>,
+    last_used: u64, // For LRU
+    filled_at: u64, // For FIFO
+}
+
+// NOTE: Cache Definition
+// Sits between the CPU and a backing memory slice and holds real copies of the data,
+// so with write-back the memory view can be stale until a dirty line is evicted
+#[derive(Clone)]
+pub struct Cache {
+    pub config: CacheConfig,
+    pub sets: Vec<Vec<CacheLine>>,
+    pub hits: u64,
+    pub misses: u64,
+    pub writebacks: u64,
+    clock: u64,
+    seed: u64, // xorshift state for random replacement, fixed so runs are reproducible
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        let line = CacheLine {
+            valid: false,
+            dirty: false,
+            tag: 0,
+            data: vec![0; config.line_size],
+            last_used: 0,
+            filled_at: 0,
+        };
+
+        Self {
+            config,
+            sets: vec![vec![line; config.ways]; config.sets()],
+            hits: 0,
+            misses: 0,
+            writebacks: 0,
+            clock: 0,
+            seed: 0x2545_F491_4F6C_DD1D,
+        }
+    }
+
+    // Returns the value and whether it was a hit
+    pub fn read(&mut self, memory: &mut [u16], address: usize) -> (u16, bool) {
+        let (set, tag, offset) = self.split(address);
+        let (way, hit) = self.lookup_or_fill(memory, set, tag);
+        (self.sets[set][way].data[offset], hit)
+    }
+
+    // Returns whether the write hit
+    pub fn write(&mut self, memory: &mut [u16], address: usize, value: u16) -> bool {
+        let (set, tag, offset) = self.split(address);
+
+        match self.config.write_policy {
+            WritePolicy::WriteBack => {
+                let (way, hit) = self.lookup_or_fill(memory, set, tag);
+                let line = &mut self.sets[set][way];
+                line.data[offset] = value;
+                line.dirty = true;
+                hit
+            }
+            WritePolicy::WriteThrough => {
+                memory[address] = value;
+
+                let hit = self.find(set, tag);
+                match hit {
+                    Some(way) => {
+                        self.hits += 1;
+                        self.touch(set, way);
+                        self.sets[set][way].data[offset] = value;
+                    }
+                    None => self.misses += 1,
+                }
+                hit.is_some()
+            }
+        }
+    }
+
+    // Copy dirty lines into memory without touching the cache state
+    pub fn write_dirty_lines(&self, memory: &mut [u16]) {
+        let line_size = self.config.line_size;
+        let sets = self.config.sets();
+
+        for (set, lines) in self.sets.iter().enumerate() {
+            for line in lines.iter().filter(|line| line.valid && line.dirty) {
+                let base = (line.tag * sets + set) * line_size;
+                for (i, &word) in line.data.iter().enumerate() {
+                    if let Some(cell) = memory.get_mut(base + i) {
+                        *cell = word;
+                    }
+                }
+            }
+        }
+    }
+
+    // Update a cached copy in place, used for debugger edits so no stats change
+    pub fn patch(&mut self, address: usize, value: u16) {
+        let (set, tag, offset) = self.split(address);
+        if let Some(way) = self.find(set, tag) {
+            self.sets[set][way].data[offset] = value;
+        }
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        let accesses = self.hits + self.misses;
+        if accesses == 0 {
+            0.0
+        } else {
+            self.hits as f64 / accesses as f64 * 100.0
+        }
+    }
+
+    // Helper functions
+    fn split(&self, address: usize) -> (usize, usize, usize) {
+        let block = address / self.config.line_size;
+        let offset = address % self.config.line_size;
+        let set = block % self.config.sets();
+        let tag = block / self.config.sets();
+        (set, tag, offset)
+    }
+
+    fn find(&self, set: usize, tag: usize) -> Option<usize> {
+        self.sets[set]
+            .iter()
+            .position(|line| line.valid && line.tag == tag)
+    }
+
+    fn touch(&mut self, set: usize, way: usize) {
+        self.clock += 1;
+        self.sets[set][way].last_used = self.clock;
+    }
+
+    fn lookup_or_fill(&mut self, memory: &mut [u16], set: usize, tag: usize) -> (usize, bool) {
+        if let Some(way) = self.find(set, tag) {
+            self.hits += 1;
+            self.touch(set, way);
+            return (way, true);
+        }
+
+        self.misses += 1;
+        let way = self.victim(set);
+        let line_size = self.config.line_size;
+        let sets = self.config.sets();
+        let line = &mut self.sets[set][way];
+
+        // Write the old block back before replacing it
+        if line.valid && line.dirty {
+            let base = (line.tag * sets + set) * line_size;
+            for (i, &word) in line.data.iter().enumerate() {
+                if let Some(cell) = memory.get_mut(base + i) {
+                    *cell = word;
+                }
+            }
+            self.writebacks += 1;
+        }
+
+        let base = (tag * sets + set) * line_size;
+        for (i, word) in line.data.iter_mut().enumerate() {
+            *word = memory.get(base + i).copied().unwrap_or(0);
+        }
+        line.valid = true;
+        line.dirty = false;
+        line.tag = tag;
+        line.filled_at = self.clock;
+
+        self.touch(set, way);
+        (way, false)
+    }
+
+    // Invalid lines are used first, otherwise the replacement policy picks
+    fn victim(&mut self, set: usize) -> usize {
+        let lines = &self.sets[set];
+        if let Some(way) = lines.iter().position(|line| !line.valid) {
+            return way;
+        }
+
+        match self.config.replacement {
+            Replacement::Lru => (0..lines.len()).min_by_key(|&way| lines[way].last_used),
+            Replacement::Fifo => (0..lines.len()).min_by_key(|&way| lines[way].filled_at),
+            Replacement::Random => {
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 7;
+                self.seed ^= self.seed << 17;
+                Some(self.seed as usize % self.config.ways)
+            }
+        }
+        .unwrap_or(0)
+    }
+}
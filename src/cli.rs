@@ -0,0 +1,161 @@
+use crate::cache::CacheConfig;
+use crate::cpu::OpCode;
+use crate::predictor::PredictorKind;
+
+use std::io;
+
+// NOTE: Command line options
+// cpu-sim [--headless] [--cost OPCODE=CYCLES]... [--pipeline] [--forwarding]
+//         [--predictor not-taken|1bit|2bit|btb] [--cache SPEC] [--harvard] [--split-cache]
+//         [--microcoded] [--microcode ROM] [--dump-microcode] [--snapshot FILE]
+//         [--load-snapshot FILE] [--save-snapshot FILE] [--trace FILE.csv|FILE.jsonl]
+//         [--diff REFERENCE.bin|TRACE] [--script FILE] [--keys FILE]
+//         [--max-instructions N] [program.bin]
+pub struct Options {
+    pub program_path: String,
+    pub headless: bool,
+    pub cycle_costs: Vec<(u8, u64)>,
+    pub pipeline: bool,
+    pub forwarding: bool,
+    pub predictor: PredictorKind,
+    pub cache: Option<CacheConfig>,
+    pub harvard: bool,
+    pub split_cache: bool,
+    pub microcoded: bool, // Microprogrammed control unit instead of hard-wired
+    pub microcode_path: Option<String>, // ROM file, built-in ROM when unset
+    pub dump_microcode: bool,
+    pub snapshot_path: String,         // Used by the TUI save/load keys
+    pub load_snapshot: Option<String>, // Start from this state instead of the program
+    pub save_snapshot: Option<String>, // Final state is written here when the run ends
+    pub trace_path: Option<String>,    // Execution trace written when the run ends
+    pub diff: Option<String>,          // Binary or trace to compare the program against
+    pub script: Option<String>,        // Debugger commands run headless instead of running freely
+    pub keys_path: Option<String>,     // Key bindings for the TUI
+    pub max_instructions: Option<u64>, // Budget before a run is stopped as a runaway
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            program_path: String::from("program.bin"),
+            headless: false,
+            cycle_costs: Vec::default(),
+            pipeline: false,
+            forwarding: false,
+            predictor: PredictorKind::default(),
+            cache: None,
+            harvard: false,
+            split_cache: false,
+            microcoded: false,
+            microcode_path: None,
+            dump_microcode: false,
+            snapshot_path: String::from("cpu.snapshot"),
+            load_snapshot: None,
+            save_snapshot: None,
+            trace_path: None,
+            diff: None,
+            script: None,
+            keys_path: None,
+            max_instructions: None,
+        }
+    }
+}
+
+pub fn parse_args() -> io::Result<Options> {
+    let mut options = Options::default();
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--headless" => options.headless = true,
+            "--pipeline" => options.pipeline = true,
+            "--forwarding" => {
+                options.pipeline = true;
+                options.forwarding = true;
+            }
+            "--predictor" => {
+                let value = next_value(&mut args, "--predictor")?;
+                options.pipeline = true;
+                options.predictor = PredictorKind::from_name(&value)
+                    .ok_or_else(|| invalid(format!("Unknown predictor {value}")))?;
+            }
+            "--harvard" => options.harvard = true,
+            "--split-cache" => options.split_cache = true,
+            "--cache" => {
+                let value = next_value(&mut args, "--cache")?;
+                options.cache = Some(CacheConfig::parse(&value).map_err(invalid)?);
+            }
+            "--microcoded" => options.microcoded = true,
+            "--microcode" => {
+                options.microcoded = true;
+                options.microcode_path = Some(next_value(&mut args, "--microcode")?);
+            }
+            "--dump-microcode" => options.dump_microcode = true,
+            "--snapshot" => options.snapshot_path = next_value(&mut args, "--snapshot")?,
+            "--load-snapshot" => {
+                options.load_snapshot = Some(next_value(&mut args, "--load-snapshot")?);
+            }
+            "--save-snapshot" => {
+                options.save_snapshot = Some(next_value(&mut args, "--save-snapshot")?);
+            }
+            "--trace" => options.trace_path = Some(next_value(&mut args, "--trace")?),
+            "--diff" => options.diff = Some(next_value(&mut args, "--diff")?),
+            "--script" => {
+                options.headless = true;
+                options.script = Some(next_value(&mut args, "--script")?);
+            }
+            "--keys" => options.keys_path = Some(next_value(&mut args, "--keys")?),
+            "--max-instructions" => {
+                let value = next_value(&mut args, "--max-instructions")?;
+                let budget = value
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&budget| budget > 0)
+                    .ok_or_else(|| invalid(format!("Invalid instruction budget {value}")))?;
+                options.max_instructions = Some(budget);
+            }
+            "--cost" => {
+                let value = next_value(&mut args, "--cost")?;
+                options.cycle_costs.push(parse_cost(&value)?);
+            }
+            _ if arg.starts_with("--") => return Err(invalid(format!("Unknown option {arg}"))),
+            _ => options.program_path = arg,
+        }
+    }
+
+    if options.split_cache && options.cache.is_none() {
+        return Err(invalid(String::from("--split-cache needs --cache")));
+    }
+    if options.microcoded && options.pipeline {
+        return Err(invalid(String::from(
+            "Microcoded control can't be combined with the pipeline",
+        )));
+    }
+
+    Ok(options)
+}
+
+// Helper functions for parsing
+fn next_value(args: &mut impl Iterator<Item = String>, option: &str) -> io::Result<String> {
+    args.next()
+        .ok_or_else(|| invalid(format!("{option} expects a value")))
+}
+
+// Parses OPCODE=CYCLES, e.g. LOAD=4
+fn parse_cost(value: &str) -> io::Result<(u8, u64)> {
+    let (name, cycles) = value
+        .split_once('=')
+        .ok_or_else(|| invalid(format!("Expected OPCODE=CYCLES, got {value}")))?;
+
+    let opcode =
+        OpCode::from_mnemonic(name).ok_or_else(|| invalid(format!("Unknown opcode {name}")))?;
+    let cycles = cycles
+        .parse::<u64>()
+        .map_err(|_| invalid(format!("Invalid cycle count {cycles}")))?;
+
+    Ok((opcode, cycles))
+}
+
+pub fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
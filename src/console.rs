@@ -0,0 +1,101 @@
+use crate::debugger::{COMMANDS, INFO_TOPICS};
+
+// Older output lines are dropped past this
+const OUTPUT_LIMIT: usize = 500;
+
+// NOTE: Command console
+// Line editor for debugger commands with history (Up/Down) and tab completion,
+// the App runs whatever submit() returns
+#[derive(Default)]
+pub struct Console {
+    pub open: bool,
+    pub input: String,
+    pub output: Vec<String>,
+    pub history: Vec<String>,
+    history_index: Option<usize>, // Entry shown while browsing history
+}
+
+impl Console {
+    pub fn print(&mut self, line: String) {
+        self.output.push(line);
+        if self.output.len() > OUTPUT_LIMIT {
+            self.output.remove(0);
+        }
+    }
+
+    // Take the input line, echo it and remember it unless it repeats the last one
+    pub fn submit(&mut self) -> String {
+        let line = std::mem::take(&mut self.input);
+        self.history_index = None;
+        self.print(format!("(sim) {line}"));
+        let entry = line.trim().to_string();
+        if !entry.is_empty() && self.history.last() != Some(&entry) {
+            self.history.push(entry);
+        }
+        line
+    }
+
+    pub fn history_up(&mut self) {
+        let index = match self.history_index {
+            Some(index) => index.saturating_sub(1),
+            None if self.history.is_empty() => return,
+            None => self.history.len() - 1,
+        };
+        self.history_index = Some(index);
+        self.input = self.history[index].clone();
+    }
+
+    // Past the newest entry the input is cleared
+    pub fn history_down(&mut self) {
+        let Some(index) = self.history_index else {
+            return;
+        };
+        if index + 1 < self.history.len() {
+            self.history_index = Some(index + 1);
+            self.input = self.history[index + 1].clone();
+        } else {
+            self.history_index = None;
+            self.input.clear();
+        }
+    }
+
+    // Completes the command name, or the topic after info. A unique match is filled in,
+    // otherwise the common prefix is and the candidates are listed
+    pub fn complete(&mut self) {
+        let (prefix, partial, candidates): (&str, &str, &[&str]) = match self.input.split_once(' ')
+        {
+            Some((command, topic)) if command == "info" || command == "i" => (
+                &self.input[..command.len() + 1],
+                topic.trim_start(),
+                &INFO_TOPICS,
+            ),
+            Some(_) => return,
+            None => ("", self.input.as_str(), &COMMANDS),
+        };
+
+        let matches: Vec<&str> = candidates
+            .iter()
+            .copied()
+            .filter(|candidate| candidate.starts_with(partial))
+            .collect();
+        let completion = match matches[..] {
+            [] => return,
+            [only] => format!("{prefix}{only} "),
+            _ => {
+                let common = matches.iter().fold(matches[0], |common, candidate| {
+                    let length = common
+                        .chars()
+                        .zip(candidate.chars())
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    &common[..length]
+                });
+                let completion = format!("{prefix}{common}");
+                let candidates = matches.join("  ");
+                self.print(candidates);
+                completion
+            }
+        };
+        self.input = completion;
+    }
+}
@@ -1,3 +1,6 @@
+use crate::cache::Cache;
+use crate::timer::{IO_BASE, Timer};
+
 // NOTE: OpCode Definition
 pub enum OpCode {
     HALT = 0b0000,
@@ -8,9 +11,47 @@ pub enum OpCode {
     SUB = 0b0101,
     JMP = 0b0110,
     MOV = 0b0111,
+    RETI = 0b1000,
+    JNZ = 0b1001,
+    CALL = 0b1010,
+    RET = 0b1011,
 }
 
+// Mnemonics indexed by opcode value
+const MNEMONICS: [&str; 12] = [
+    "HALT", "LVAL", "LOAD", "STORE", "ADD", "SUB", "JMP", "MOV", "RETI", "JNZ", "CALL", "RET",
+];
+
+// NOTE: Timing model
+// Cycles spent per opcode, memory ops and control flow cost more than register ops
+pub const DEFAULT_CYCLE_COSTS: [u64; 16] = [
+    1, // HALT
+    1, // LVAL
+    3, // LOAD
+    3, // STORE
+    1, // ADD
+    1, // SUB
+    2, // JMP
+    1, // MOV
+    2, // RETI
+    2, // JNZ
+    3, // CALL
+    3, // RET
+    1, 1, 1, 1, // Unused
+];
+
 impl OpCode {
+    pub fn mnemonic(value: u8) -> Option<&'static str> {
+        MNEMONICS.get(value as usize).copied()
+    }
+
+    pub fn from_mnemonic(name: &str) -> Option<u8> {
+        MNEMONICS
+            .iter()
+            .position(|mnemonic| mnemonic.eq_ignore_ascii_case(name))
+            .map(|index| index as u8)
+    }
+
     // Helper function to decode value to corresponding OpCode
     pub fn u8_to_opcode(value: u8) -> Option<OpCode> {
         match value {
@@ -22,11 +63,58 @@ impl OpCode {
             0b0101 => Some(OpCode::SUB),
             0b0110 => Some(OpCode::JMP),
             0b0111 => Some(OpCode::MOV),
+            0b1000 => Some(OpCode::RETI),
+            0b1001 => Some(OpCode::JNZ),
+            0b1010 => Some(OpCode::CALL),
+            0b1011 => Some(OpCode::RET),
             _ => None,
         }
     }
 }
 
+// Split an instruction word into (opcode, register, operand)
+pub fn decode_word(word: u16) -> (u8, u8, u8) {
+    let opcode = ((word >> 12) & 0b1111) as u8;
+    let register = ((word >> 8) & 0b1111) as u8;
+    let operand = (word & 0xFF) as u8;
+    (opcode, register, operand)
+}
+
+// Turn an instruction word back into assembly, e.g. "ADD R0, R1"
+pub fn disassemble(word: u16) -> String {
+    let (opcode, register, operand) = decode_word(word);
+    let Some(name) = OpCode::mnemonic(opcode) else {
+        return format!("??? {word:#06x}");
+    };
+
+    use OpCode::*;
+    match OpCode::u8_to_opcode(opcode) {
+        Some(HALT | RETI | RET) => name.to_string(),
+        Some(JMP | CALL) => format!("{name} {operand}"),
+        Some(ADD | SUB | MOV) => format!("{name} R{}, R{}", register & 0b11, operand & 0b11),
+        _ => format!("{name} R{}, {operand}", register & 0b11),
+    }
+}
+
+// Read a word through an optional cache, adding the miss penalty to stall
+fn read_through(
+    cache: Option<&mut Cache>,
+    memory: &mut [u16],
+    stall: &mut u64,
+    address: usize,
+) -> u16 {
+    match cache {
+        Some(cache) => {
+            let (value, hit) = cache.read(memory, address);
+            if !hit {
+                *stall += cache.config.miss_penalty;
+            }
+            value
+        }
+        None => memory[address],
+    }
+}
+
 // NOTE: CPU Definition
 pub struct CPU {
     r0: u16,
@@ -35,8 +123,27 @@ pub struct CPU {
     r3: u16,
     pub pc: u16,
     pub ir: u16,
+    pub sp: u16,  // Stack pointer, the stack grows down from the top of memory
+    pub mar: u16, // Memory address register
+    pub mdr: u16, // Memory data register
+    pub alu_a: u16,
+    pub alu_b: u16,
+    pub alu_out: u16,
     pub memory: [u16; 64],
+    pub instruction_memory: Option<[u16; 64]>, // Separate fetch memory when Harvard
     pub halted: bool,
+    pub cycles: u64,
+    pub instructions: u64,
+    pub cycle_costs: [u64; 16],
+    pub epc: u16, // PC saved when an interrupt is taken
+    pub in_interrupt: bool,
+    pub timer: Timer,
+    pub cache: Option<Cache>, // Unified cache, or data cache when icache is set
+    pub icache: Option<Cache>, // Instruction cache for split I/D caches
+    pub memory_stall: u64,    // Miss cycles not yet accounted for
+    pub fetch_address: u16,   // Address the word in IR was fetched from
+    pub memory_writes: Vec<(u8, u16)>, // Stores by the last instruction (cycle when pipelined)
+    pub fault: Option<String>, // Why the CPU stopped, when it wasn't a HALT
 }
 
 impl Default for CPU {
@@ -48,8 +155,27 @@ impl Default for CPU {
             r3: 0,
             pc: 0,
             ir: 0,
+            sp: 64, // Empty stack, the first push lands in the last word
+            mar: 0,
+            mdr: 0,
+            alu_a: 0,
+            alu_b: 0,
+            alu_out: 0,
             memory: [0; 64],
+            instruction_memory: None,
             halted: false,
+            cycles: 0,
+            instructions: 0,
+            cycle_costs: DEFAULT_CYCLE_COSTS,
+            epc: 0,
+            in_interrupt: false,
+            timer: Timer::default(),
+            cache: None,
+            icache: None,
+            memory_stall: 0,
+            fetch_address: 0,
+            memory_writes: Vec::new(),
+            fault: None,
         }
     }
 }
@@ -65,24 +191,113 @@ impl CPU {
         }
     }
 
+    // Reload program but keep configuration (cycle costs, caches, architecture)
+    pub fn reset(&mut self, program: &[u16]) {
+        let harvard = self.instruction_memory.is_some();
+        *self = Self {
+            cycle_costs: self.cycle_costs,
+            cache: self.cache.as_ref().map(|cache| Cache::new(cache.config)),
+            icache: self.icache.as_ref().map(|cache| Cache::new(cache.config)),
+            ..Self::new(program)
+        };
+
+        if harvard {
+            self.split_memories();
+        }
+    }
+
+    // Switch to a Harvard machine, the program image is copied into a separate
+    // instruction memory so fetches never see STOREs
+    pub fn split_memories(&mut self) {
+        self.instruction_memory = Some(self.memory);
+    }
+
+    // Fetches and data accesses can happen in the same cycle
+    pub fn has_split_ports(&self) -> bool {
+        self.instruction_memory.is_some() || self.icache.is_some()
+    }
+
+    // NOTE: Step
+    // One full instruction cycle, devices are ticked afterwards
+    pub fn step(&mut self) {
+        self.begin_instruction();
+
+        self.fetch();
+        let (opcode, register, operand) = self.decode();
+        self.execute(opcode, register, operand);
+
+        self.finish_instruction(opcode);
+    }
+
+    // Pending interrupts are taken between instructions
+    pub fn begin_instruction(&mut self) {
+        self.memory_writes.clear();
+        if self.timer.irq() && !self.in_interrupt {
+            self.interrupt();
+        }
+    }
+
+    // Account for the instruction's cycles and let devices catch up
+    pub fn finish_instruction(&mut self, opcode: u8) {
+        self.account_cycles(self.cycle_costs[opcode as usize]);
+    }
+
+    pub fn account_cycles(&mut self, cycles: u64) {
+        // Cache misses add their latency on top of the instruction's own cycles
+        let cost = cycles + self.memory_stall;
+        self.memory_stall = 0;
+        self.cycles += cost;
+        self.instructions += 1;
+        self.timer.tick(cost);
+    }
+
+    // Average cycles per instruction over the run so far
+    pub fn cpi(&self) -> f64 {
+        if self.instructions == 0 {
+            0.0
+        } else {
+            self.cycles as f64 / self.instructions as f64
+        }
+    }
+
+    // Stop on something the program or microcode can't go on from, the runners
+    // report it like a runaway
+    pub fn trap(&mut self, reason: String) {
+        self.fault = Some(reason);
+        self.halted = true;
+    }
+
+    // Opcodes without an instruction (12 to 15) stop the CPU
+    pub fn illegal_instruction(&mut self, address: u16, word: u16) {
+        self.trap(format!("Illegal instruction 0x{word:04X} at {address}"));
+    }
+
+    // Save PC and jump to the handler set in the timer's vector register
+    fn interrupt(&mut self) {
+        self.epc = self.pc;
+        self.pc = self.timer.vector;
+        self.in_interrupt = true;
+    }
+
     // NOTE: Fetch
     pub fn fetch(&mut self) {
-        self.ir = self.memory[self.pc as usize];
+        self.mar = self.pc;
+        self.mdr = self.fetch_word(self.pc);
+        self.ir = self.mdr;
         self.pc = self.pc.wrapping_add(1);
     }
 
     // NOTE: Decode
     pub fn decode(&self) -> (u8, u8, u8) {
-        let opcode = ((self.ir >> 12) & 0b1111) as u8;
-        let register = ((self.ir >> 8) & 0b1111) as u8;
-        let operand = (self.ir & 0xFF) as u8;
-        (opcode, register, operand)
+        decode_word(self.ir)
     }
 
     // NOTE: Execute
     pub fn execute(&mut self, opcode: u8, register: u8, operand: u8) {
-        let opcode = OpCode::u8_to_opcode(opcode)
-            .unwrap_or_else(|| panic!("Unknown opcode: {:#04b}", opcode));
+        let Some(opcode) = OpCode::u8_to_opcode(opcode) else {
+            self.illegal_instruction(self.fetch_address, self.ir);
+            return;
+        };
 
         use OpCode::*;
         match opcode {
@@ -97,30 +312,33 @@ impl CPU {
 
             LOAD => {
                 // Load value from memory[i]
-                *self.get_register_mut(register) = self.memory[operand as usize];
+                self.mar = operand as u16;
+                self.mdr = self.read_memory(operand);
+                *self.get_register_mut(register) = self.mdr;
             }
 
             STORE => {
-                let value = self.get_register(register);
+                self.mar = operand as u16;
+                self.mdr = self.get_register(register);
 
                 // Store current value in register into memory
-                self.memory[operand as usize] = value;
+                self.write_memory(operand, self.mdr);
             }
 
             // NOTE: For ADD and SUB
             // wrapping_add/sub prevents integer overflow
             ADD => {
-                let dest = self.get_register(register);
-                let src = self.get_register(operand);
-                let dest_reg_mut = self.get_register_mut(register);
-                *dest_reg_mut = dest.wrapping_add(src);
+                self.alu_a = self.get_register(register);
+                self.alu_b = self.get_register(operand);
+                self.alu_out = self.alu_a.wrapping_add(self.alu_b);
+                *self.get_register_mut(register) = self.alu_out;
             }
 
             SUB => {
-                let dest = self.get_register(register);
-                let src = self.get_register(operand);
-                let dest_reg_mut = self.get_register_mut(register);
-                *dest_reg_mut = dest.wrapping_sub(src);
+                self.alu_a = self.get_register(register);
+                self.alu_b = self.get_register(operand);
+                self.alu_out = self.alu_a.wrapping_sub(self.alu_b);
+                *self.get_register_mut(register) = self.alu_out;
             }
 
             JMP => {
@@ -129,13 +347,140 @@ impl CPU {
 
             MOV => {
                 // This is technically copy lol
-                let src = self.get_register(operand);
-                let dest = self.get_register_mut(register);
-                *dest = src;
+                // Value passes straight through the ALU
+                self.alu_b = self.get_register(operand);
+                self.alu_out = self.alu_b;
+                *self.get_register_mut(register) = self.alu_out;
+            }
+
+            RETI => {
+                // Return from interrupt handler
+                self.pc = self.epc;
+                self.in_interrupt = false;
+            }
+
+            JNZ => {
+                self.alu_a = self.get_register(register);
+                if self.alu_a != 0 {
+                    self.pc = operand as u16;
+                }
+            }
+
+            CALL => {
+                // Push the return address, then jump
+                self.sp = self.sp.wrapping_sub(1);
+                self.mar = self.sp;
+                self.mdr = self.pc;
+                self.write_memory(self.mar as u8, self.mdr);
+                self.pc = operand as u16;
+            }
+
+            RET => {
+                // Pop the return address
+                self.mar = self.sp;
+                self.mdr = self.read_memory(self.mar as u8);
+                self.sp = self.sp.wrapping_add(1);
+                self.pc = self.mdr;
             }
         }
     }
 
+    // NOTE: Memory access
+    // Addresses from IO_BASE are memory-mapped devices (never cached),
+    // unmapped addresses read as 0 and ignore writes
+    pub fn read_memory(&mut self, address: u8) -> u16 {
+        if address >= IO_BASE {
+            self.timer.read(address)
+        } else if (address as usize) < self.memory.len() {
+            self.cached_read(address as usize)
+        } else {
+            0
+        }
+    }
+
+    pub fn write_memory(&mut self, address: u8, value: u16) {
+        self.memory_writes.push((address, value));
+        if address >= IO_BASE {
+            self.timer.write(address, value);
+        } else if (address as usize) < self.memory.len() {
+            self.cached_write(address as usize, value);
+        }
+    }
+
+    // Memory with dirty cache lines written back, what a flush would leave behind
+    pub fn flushed_memory(&self) -> [u16; 64] {
+        let mut memory = self.memory;
+        if let Some(cache) = &self.cache {
+            cache.write_dirty_lines(&mut memory);
+        }
+        memory
+    }
+
+    // Edit from the debugger, bypasses timing and keeps cached copies in step.
+    // On Harvard the instruction cache (or the unified one) fronts instruction memory
+    pub fn poke_memory(&mut self, address: usize, value: u16, instruction: bool) {
+        if address >= self.memory.len() {
+            return;
+        }
+
+        let caches = match &mut self.instruction_memory {
+            Some(instruction_memory) if instruction => {
+                instruction_memory[address] = value;
+                [self.icache.as_mut().or(self.cache.as_mut()), None]
+            }
+            Some(_) => {
+                self.memory[address] = value;
+                [self.cache.as_mut(), None]
+            }
+            None => {
+                self.memory[address] = value;
+                [self.cache.as_mut(), self.icache.as_mut()]
+            }
+        };
+        for cache in caches.into_iter().flatten() {
+            cache.patch(address, value);
+        }
+    }
+
+    // Instruction fetch, running off the end of memory reads 0 (HALT)
+    pub fn fetch_word(&mut self, address: u16) -> u16 {
+        self.fetch_address = address;
+        let address = address as usize;
+        if address >= self.memory.len() {
+            return 0;
+        }
+
+        let memory = match &mut self.instruction_memory {
+            Some(instruction_memory) => instruction_memory,
+            None => &mut self.memory,
+        };
+        let cache = match &mut self.icache {
+            Some(icache) => Some(icache),
+            None => self.cache.as_mut(),
+        };
+        read_through(cache, memory, &mut self.memory_stall, address)
+    }
+
+    fn cached_read(&mut self, address: usize) -> u16 {
+        read_through(
+            self.cache.as_mut(),
+            &mut self.memory,
+            &mut self.memory_stall,
+            address,
+        )
+    }
+
+    fn cached_write(&mut self, address: usize, value: u16) {
+        match &mut self.cache {
+            Some(cache) => {
+                if !cache.write(&mut self.memory, address, value) {
+                    self.memory_stall += cache.config.miss_penalty;
+                }
+            }
+            None => self.memory[address] = value,
+        }
+    }
+
     // More helper functions
     pub fn get_register(&self, index: u8) -> u16 {
         match index & 0b11 {
@@ -161,11 +506,4 @@ impl CPU {
     pub fn get_all_registers(&self) -> [u16; 4] {
         [self.r0, self.r1, self.r2, self.r3]
     }
-
-    pub fn log_registers(&self) -> String {
-        format!(
-            " {:2} │ {:4} │ {:4} │ {:4} │ {:4} ",
-            self.pc, self.r0, self.r1, self.r2, self.r3,
-        )
-    }
 }
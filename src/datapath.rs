@@ -0,0 +1,207 @@
+use crate::cpu::{CPU, disassemble};
+use crate::micro::MicroOp::{self, *};
+
+use ratatui::{
+    style::{Color, Style},
+    text::Span,
+    widgets::{
+        Block, Borders, Widget,
+        canvas::{Canvas, Context, Line, Rectangle},
+    },
+};
+
+// NOTE: Datapath components
+#[derive(Clone, Copy, PartialEq)]
+enum Unit {
+    Pc,
+    Ir,
+    Mar,
+    Mdr,
+    Memory,
+    Registers,
+    Alu,
+}
+
+// Buses between components, highlighted while a transfer uses them
+#[derive(Clone, Copy, PartialEq)]
+enum Bus {
+    PcMar,
+    MarMemory,
+    MemoryMdr,
+    MdrIr,
+    IrMar,
+    IrPc,
+    IrRegisters,
+    MdrRegisters,
+    RegistersAluA,
+    RegistersAluB,
+    AluRegisters,
+}
+
+// Box position and size in canvas coordinates (x 0..100, y 0..50)
+fn bounds(unit: Unit) -> (f64, f64, f64, f64) {
+    match unit {
+        Unit::Pc => (2.0, 40.0, 26.0, 7.0),
+        Unit::Ir => (2.0, 27.0, 26.0, 7.0),
+        Unit::Mar => (38.0, 40.0, 22.0, 7.0),
+        Unit::Mdr => (38.0, 27.0, 22.0, 7.0),
+        Unit::Memory => (70.0, 27.0, 28.0, 20.0),
+        Unit::Registers => (2.0, 2.0, 26.0, 18.0),
+        Unit::Alu => (62.0, 2.0, 30.0, 16.0),
+    }
+}
+
+// Bus endpoints in canvas coordinates
+fn wire(bus: Bus) -> [(f64, f64, f64, f64); 2] {
+    let single = |x1, y1, x2, y2| [(x1, y1, x2, y2), (x1, y1, x2, y2)];
+    match bus {
+        Bus::PcMar => single(28.0, 44.0, 38.0, 44.0),
+        Bus::MarMemory => single(60.0, 44.0, 70.0, 44.0),
+        Bus::MemoryMdr => single(70.0, 30.0, 60.0, 30.0),
+        Bus::MdrIr => single(38.0, 30.0, 28.0, 30.0),
+        Bus::IrMar => [(28.0, 32.0, 33.0, 32.0), (33.0, 32.0, 38.0, 42.0)],
+        Bus::IrPc => single(10.0, 34.0, 10.0, 40.0),
+        Bus::IrRegisters => single(10.0, 27.0, 10.0, 20.0),
+        Bus::MdrRegisters => [(49.0, 27.0, 49.0, 22.0), (49.0, 22.0, 24.0, 20.0)],
+        Bus::RegistersAluA => single(28.0, 14.0, 62.0, 14.0),
+        Bus::RegistersAluB => single(28.0, 10.0, 62.0, 10.0),
+        Bus::AluRegisters => [(77.0, 2.0, 77.0, 0.5), (77.0, 0.5, 15.0, 2.0)],
+    }
+}
+
+// Components and buses a micro-op drives
+fn activity(op: MicroOp) -> (&'static [Unit], &'static [Bus]) {
+    match op {
+        PcToMar => (&[Unit::Pc, Unit::Mar], &[Bus::PcMar]),
+        ReadInstruction | ReadMemory => (
+            &[Unit::Mar, Unit::Memory, Unit::Mdr],
+            &[Bus::MarMemory, Bus::MemoryMdr],
+        ),
+        WriteMemory => (
+            &[Unit::Mar, Unit::Memory, Unit::Mdr],
+            &[Bus::MarMemory, Bus::MemoryMdr],
+        ),
+        IncrementPc | EpcToPc => (&[Unit::Pc], &[]),
+        SpToMar => (&[Unit::Mar], &[]),
+        SpDec | SpInc => (&[], &[]),
+        PcToMdr | MdrToPc => (&[Unit::Pc, Unit::Mdr], &[]),
+        MdrToIr => (&[Unit::Mdr, Unit::Ir], &[Bus::MdrIr]),
+        Decode => (&[Unit::Ir], &[]),
+        OperandToMar => (&[Unit::Ir, Unit::Mar], &[Bus::IrMar]),
+        OperandToReg => (&[Unit::Ir, Unit::Registers], &[Bus::IrRegisters]),
+        MdrToReg | RegToMdr => (&[Unit::Mdr, Unit::Registers], &[Bus::MdrRegisters]),
+        RegToAluA => (&[Unit::Registers, Unit::Alu], &[Bus::RegistersAluA]),
+        RegToAluB => (&[Unit::Registers, Unit::Alu], &[Bus::RegistersAluB]),
+        AluAdd | AluSub | AluPassB | AluInc | AluDec | AluAnd | AluOr | AluXor | AluShl
+        | AluShr => (&[Unit::Alu], &[]),
+        AluOutToReg => (&[Unit::Alu, Unit::Registers], &[Bus::AluRegisters]),
+        OperandToPc => (&[Unit::Ir, Unit::Pc], &[Bus::IrPc]),
+        BranchIfNonZero | BranchIfZero => (&[Unit::Alu, Unit::Ir, Unit::Pc], &[Bus::IrPc]),
+        Halt => (&[], &[]),
+    }
+}
+
+const ACTIVE: Color = Color::Yellow;
+const IDLE: Color = Color::DarkGray;
+
+// NOTE: Datapath widget
+// Block diagram of the single-cycle datapath, components and buses used by
+// `active` micro-ops are highlighted
+pub fn widget<'a>(cpu: &'a CPU, active: &'a [MicroOp], phase: &'a str) -> impl Widget + 'a {
+    let units: Vec<Unit> = active
+        .iter()
+        .flat_map(|&op| activity(op).0.iter().copied())
+        .collect();
+    let buses: Vec<Bus> = active
+        .iter()
+        .flat_map(|&op| activity(op).1.iter().copied())
+        .collect();
+
+    Canvas::default()
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("Datapath ({phase})")),
+        )
+        .x_bounds([0.0, 100.0])
+        .y_bounds([0.0, 50.0])
+        .paint(move |ctx| {
+            let color = |on: bool| if on { ACTIVE } else { IDLE };
+
+            for bus in [
+                Bus::PcMar,
+                Bus::MarMemory,
+                Bus::MemoryMdr,
+                Bus::MdrIr,
+                Bus::IrMar,
+                Bus::IrPc,
+                Bus::IrRegisters,
+                Bus::MdrRegisters,
+                Bus::RegistersAluA,
+                Bus::RegistersAluB,
+                Bus::AluRegisters,
+            ] {
+                for (x1, y1, x2, y2) in wire(bus) {
+                    ctx.draw(&Line::new(x1, y1, x2, y2, color(buses.contains(&bus))));
+                }
+            }
+
+            let registers = cpu.get_all_registers();
+            let memory_value = cpu.memory.get(cpu.mar as usize).copied().unwrap_or(0);
+            let labels: [(Unit, Vec<String>); 7] = [
+                (Unit::Pc, vec![format!("PC {}", cpu.pc)]),
+                (Unit::Ir, vec![format!("IR {}", disassemble(cpu.ir))]),
+                (Unit::Mar, vec![format!("MAR {}", cpu.mar)]),
+                (Unit::Mdr, vec![format!("MDR {}", cpu.mdr)]),
+                (
+                    Unit::Memory,
+                    vec![
+                        String::from("Memory"),
+                        format!("M[{}]", cpu.mar),
+                        format!("= {memory_value}"),
+                    ],
+                ),
+                (
+                    Unit::Registers,
+                    registers
+                        .iter()
+                        .enumerate()
+                        .map(|(i, value)| format!("R{i} {value}"))
+                        .collect(),
+                ),
+                (
+                    Unit::Alu,
+                    vec![
+                        format!("ALU A {}", cpu.alu_a),
+                        format!("    B {}", cpu.alu_b),
+                        format!("  OUT {}", cpu.alu_out),
+                    ],
+                ),
+            ];
+
+            for (unit, lines) in labels {
+                draw_unit(ctx, unit, &lines, color(units.contains(&unit)));
+            }
+        })
+}
+
+fn draw_unit(ctx: &mut Context, unit: Unit, lines: &[String], color: Color) {
+    let (x, y, width, height) = bounds(unit);
+    ctx.draw(&Rectangle {
+        x,
+        y,
+        width,
+        height,
+        color,
+    });
+
+    // Text rows from the top of the box downwards
+    for (i, line) in lines.iter().enumerate() {
+        let row = y + height - 3.0 - i as f64 * 3.5;
+        ctx.print(
+            x + 2.0,
+            row,
+            Span::styled(line.clone(), Style::default().fg(color)),
+        );
+    }
+}
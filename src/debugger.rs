@@ -0,0 +1,516 @@
+use crate::cpu::{CPU, OpCode, decode_word, disassemble};
+use crate::edit::{self, EditTarget};
+use crate::pipeline::Pipeline;
+use crate::trace::TraceRecord;
+
+use std::collections::BTreeSet;
+
+// Command names, also used for tab completion
+pub const COMMANDS: [&str; 14] = [
+    "break", "continue", "delete", "finish", "help", "info", "next", "run", "set", "step", "until",
+    "unwatch", "watch", "x",
+];
+pub const INFO_TOPICS: [&str; 2] = ["breakpoints", "registers"];
+
+const HELP: [&str; 14] = [
+    "break ADDR        stop before the instruction at ADDR runs",
+    "delete [ADDR]     remove a breakpoint, or every breakpoint and watchpoint",
+    "watch ADDR        stop after a store to ADDR",
+    "unwatch ADDR      remove a watchpoint",
+    "set TARGET=VALUE  TARGET is R0-R3, PC, M[ADDR] or I[ADDR] (Harvard)",
+    "x/N ADDR          show N words of memory from ADDR",
+    "run [N]           run N instructions, or until something stops it",
+    "continue          same as run",
+    "step [N]          run N instructions ignoring breakpoints",
+    "next              step over a CALL, running the whole subroutine",
+    "finish            run until the current subroutine returns",
+    "until ADDR        run until the PC reaches ADDR",
+    "info registers    show PC, IR and registers",
+    "info breakpoints  list breakpoints and watchpoints",
+];
+
+// NOTE: Debugger commands
+// Parsed from one line of console or script input, e.g. "break 12" or "x/8 20"
+pub enum Command {
+    Break(u16),
+    Delete(Option<u16>),
+    Watch(u8),
+    Unwatch(u8),
+    Set(EditTarget, u16),
+    Examine { count: usize, address: usize },
+    Run(Option<u64>),
+    Step(u64),
+    Next,
+    Finish,
+    Until(u16),
+    InfoRegisters,
+    InfoBreakpoints,
+    Help,
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let line = line.trim();
+    let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim();
+    let (name, count) = word.split_once('/').unwrap_or((word, ""));
+
+    let number = |text: &str| {
+        edit::parse_number(text, u16::MAX).ok_or_else(|| format!("Invalid number {text}"))
+    };
+    let address = |text: &str| -> Result<u8, String> {
+        edit::parse_number(text, u8::MAX as u16)
+            .map(|value| value as u8)
+            .ok_or_else(|| format!("Invalid address {text}"))
+    };
+    let optional = |text: &str| -> Result<Option<u16>, String> {
+        if text.is_empty() {
+            Ok(None)
+        } else {
+            number(text).map(Some)
+        }
+    };
+    if !count.is_empty() && name != "x" {
+        return Err(format!("{name} doesn't take a /N count"));
+    }
+
+    match name {
+        "break" | "b" => Ok(Command::Break(number(rest)?)),
+        "delete" | "d" => Ok(Command::Delete(optional(rest)?)),
+        "watch" => Ok(Command::Watch(address(rest)?)),
+        "unwatch" => Ok(Command::Unwatch(address(rest)?)),
+        "set" => {
+            let (target, value) = rest
+                .split_once('=')
+                .ok_or_else(|| String::from("Expected set TARGET=VALUE"))?;
+            Ok(Command::Set(
+                parse_target(target.trim())?,
+                edit::parse_value(value)?,
+            ))
+        }
+        "x" => {
+            let count = if count.is_empty() { 1 } else { number(count)? };
+            Ok(Command::Examine {
+                count: count as usize,
+                address: number(rest)? as usize,
+            })
+        }
+        "run" | "r" | "continue" | "c" => Ok(Command::Run(optional(rest)?.map(u64::from))),
+        "step" | "s" => Ok(Command::Step(optional(rest)?.unwrap_or(1) as u64)),
+        "next" | "n" => Ok(Command::Next),
+        "finish" | "fin" => Ok(Command::Finish),
+        "until" | "u" => Ok(Command::Until(number(rest)?)),
+        "info" | "i" => match INFO_TOPICS.iter().find(|topic| topic.starts_with(rest)) {
+            Some(&"registers") if !rest.is_empty() => Ok(Command::InfoRegisters),
+            Some(&"breakpoints") if !rest.is_empty() => Ok(Command::InfoBreakpoints),
+            // GDB style short forms
+            _ if rest == "regs" => Ok(Command::InfoRegisters),
+            _ => Err(String::from("Expected info registers or info breakpoints")),
+        },
+        "help" | "h" => Ok(Command::Help),
+        "" => Err(String::from("Expected a command")),
+        _ => Err(format!("Unknown command {name}, try help")),
+    }
+}
+
+// R0-R3, PC, M[ADDR] or I[ADDR]
+fn parse_target(text: &str) -> Result<EditTarget, String> {
+    let upper = text.to_uppercase();
+    let memory = |prefix: &str| {
+        upper
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(']'))
+            .and_then(|address| edit::parse_number(address.trim(), u16::MAX))
+    };
+
+    match upper.as_str() {
+        "PC" => Ok(EditTarget::Pc),
+        "R0" | "R1" | "R2" | "R3" => Ok(EditTarget::Register(upper.as_bytes()[1] - b'0')),
+        _ if let Some(address) = memory("M[") => Ok(EditTarget::Memory {
+            address: address as usize,
+            instruction: false,
+        }),
+        _ if let Some(address) = memory("I[") => Ok(EditTarget::Memory {
+            address: address as usize,
+            instruction: true,
+        }),
+        _ => Err(format!("Unknown target {text}")),
+    }
+}
+
+// Where the program is as far as completed instructions go. When pipelined cpu.pc
+// and cpu.sp run ahead, they belong to instructions still in flight.
+#[derive(Default)]
+pub struct Position {
+    pub next_pc: u16,     // Next instruction to complete
+    pub sp: u16,          // SP the last completed instruction left
+    pub pending: Vec<u8>, // Registers instructions in flight have yet to write
+}
+
+impl Position {
+    pub fn of(cpu: &CPU, pipeline: Option<&Pipeline>) -> Self {
+        match pipeline {
+            Some(pipeline) => Self {
+                next_pc: pipeline.next_pc().unwrap_or(cpu.pc),
+                sp: pipeline.retired_sp(cpu),
+                pending: pipeline.pending_registers(),
+            },
+            None => Self {
+                next_pc: cpu.pc,
+                sp: cpu.sp,
+                pending: Vec::new(),
+            },
+        }
+    }
+}
+
+// Stop conditions of a run started by run, step, next, finish or until
+#[derive(Default)]
+struct RunLimit {
+    remaining: Option<u64>,
+    until: Option<u16>,    // Temporary breakpoint
+    frame: Option<u16>,    // ...only taken with SP at or above this, so not in a nested call
+    returned: Option<u16>, // Stop once SP rises above this, the frame was popped
+    breakpoints: bool,
+}
+
+// NOTE: Debugger Definition
+// Breakpoints and watchpoints apply to every run, the host steps the CPU and calls
+// after_step() once per completed instruction while running() or in continuous mode
+#[derive(Default)]
+pub struct Debugger {
+    pub breakpoints: BTreeSet<u16>,
+    pub watchpoints: BTreeSet<u8>,
+    run: Option<RunLimit>,
+}
+
+impl Debugger {
+    // Output lines, run commands only set up the run for the host to carry out
+    pub fn execute(
+        &mut self,
+        command: Command,
+        cpu: &mut CPU,
+        position: &Position,
+    ) -> Result<Vec<String>, String> {
+        let &Position { next_pc, sp, .. } = position;
+        let memory_size = cpu.memory.len();
+        match command {
+            Command::Break(address) => {
+                if address as usize >= memory_size {
+                    return Err(format!("Address {address} is outside memory"));
+                }
+                self.breakpoints.insert(address);
+                Ok(vec![format!("Breakpoint at {address}")])
+            }
+            Command::Delete(Some(address)) => {
+                if !self.breakpoints.remove(&address) {
+                    return Err(format!("No breakpoint at {address}"));
+                }
+                Ok(vec![format!("Deleted breakpoint at {address}")])
+            }
+            Command::Delete(None) => {
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+                Ok(vec![String::from(
+                    "Deleted all breakpoints and watchpoints",
+                )])
+            }
+            Command::Watch(address) => {
+                self.watchpoints.insert(address);
+                Ok(vec![format!("Watchpoint on M[{address}]")])
+            }
+            Command::Unwatch(address) => {
+                if !self.watchpoints.remove(&address) {
+                    return Err(format!("No watchpoint on M[{address}]"));
+                }
+                Ok(vec![format!("Deleted watchpoint on M[{address}]")])
+            }
+            Command::Set(target, value) => {
+                if let EditTarget::Memory { address, .. } = target
+                    && address >= memory_size
+                {
+                    return Err(format!("Address {address} is outside memory"));
+                }
+                target.apply(cpu, value);
+                Ok(vec![format!("{target} = {value}")])
+            }
+            Command::Examine { count, address } => {
+                let memory = cpu.flushed_memory();
+                let end = address.saturating_add(count).min(memory_size);
+                if address >= end {
+                    return Err(format!("Address {address} is outside memory"));
+                }
+                Ok((address..end)
+                    .map(|address| {
+                        let value = memory[address];
+                        format!(
+                            "{address:3}: {value:5}  {value:#06x}  {}",
+                            disassemble(value)
+                        )
+                    })
+                    .collect())
+            }
+            Command::Run(_)
+            | Command::Step(_)
+            | Command::Next
+            | Command::Finish
+            | Command::Until(_)
+                if cpu.halted =>
+            {
+                Err(String::from("The program has halted"))
+            }
+            Command::Run(count) => {
+                self.start(RunLimit {
+                    remaining: count,
+                    breakpoints: true,
+                    ..Default::default()
+                });
+                Ok(Vec::new())
+            }
+            Command::Step(count) => {
+                self.start(RunLimit {
+                    remaining: Some(count),
+                    ..Default::default()
+                });
+                Ok(Vec::new())
+            }
+            // Anything but a CALL is a single step
+            Command::Next => {
+                let memory = cpu.instruction_memory.as_ref().unwrap_or(&cpu.memory);
+                let word = memory.get(next_pc as usize).copied().unwrap_or(0);
+                let (opcode, _, _) = decode_word(word);
+                self.start(if opcode == OpCode::CALL as u8 {
+                    RunLimit {
+                        until: Some(next_pc.wrapping_add(1)),
+                        frame: Some(sp),
+                        breakpoints: true,
+                        ..Default::default()
+                    }
+                } else {
+                    RunLimit {
+                        remaining: Some(1),
+                        ..Default::default()
+                    }
+                });
+                Ok(Vec::new())
+            }
+            Command::Finish => {
+                if sp as usize >= memory_size {
+                    return Err(String::from("Not inside a subroutine (the stack is empty)"));
+                }
+                self.start(RunLimit {
+                    returned: Some(sp),
+                    breakpoints: true,
+                    ..Default::default()
+                });
+                Ok(Vec::new())
+            }
+            Command::Until(address) => {
+                self.start(RunLimit {
+                    until: Some(address),
+                    breakpoints: true,
+                    ..Default::default()
+                });
+                Ok(Vec::new())
+            }
+            // The state completed instructions left, registers still to be written by
+            // instructions in flight are marked
+            Command::InfoRegisters => {
+                let registers: Vec<String> = cpu
+                    .get_all_registers()
+                    .iter()
+                    .enumerate()
+                    .map(|(register, value)| {
+                        if position.pending.contains(&(register as u8)) {
+                            format!("R{register}: {value} (in flight)")
+                        } else {
+                            format!("R{register}: {value}")
+                        }
+                    })
+                    .collect();
+                Ok(vec![
+                    format!(
+                        "PC: {next_pc}  SP: {sp}  IR: {:#06x} ({})",
+                        cpu.ir,
+                        disassemble(cpu.ir)
+                    ),
+                    registers.join("  "),
+                    format!("Halted: {}  In interrupt: {}", cpu.halted, cpu.in_interrupt),
+                ])
+            }
+            Command::InfoBreakpoints => {
+                let mut lines: Vec<String> = self
+                    .breakpoints
+                    .iter()
+                    .map(|address| format!("Breakpoint at {address}"))
+                    .collect();
+                lines.extend(
+                    self.watchpoints
+                        .iter()
+                        .map(|address| format!("Watchpoint on M[{address}]")),
+                );
+                if lines.is_empty() {
+                    lines.push(String::from("No breakpoints or watchpoints"));
+                }
+                Ok(lines)
+            }
+            Command::Help => Ok(HELP.iter().map(|line| line.to_string()).collect()),
+        }
+    }
+
+    fn start(&mut self, run: RunLimit) {
+        self.run = Some(run);
+    }
+
+    // A run, step or until is in progress
+    pub fn running(&self) -> bool {
+        self.run.is_some()
+    }
+
+    pub fn cancel(&mut self) {
+        self.run = None;
+    }
+
+    // Called after each completed instruction with the address of the next one,
+    // returns why execution should stop
+    pub fn after_step(
+        &mut self,
+        cpu: &CPU,
+        position: &Position,
+        record: &TraceRecord,
+    ) -> Option<String> {
+        let &Position { next_pc, sp, .. } = position;
+        let breakpoints = self.run.as_ref().is_none_or(|run| run.breakpoints);
+        let watched = record
+            .memory_writes
+            .iter()
+            .find(|(address, _)| self.watchpoints.contains(address));
+
+        let reason = if let Some((address, value)) = watched
+            && breakpoints
+        {
+            Some(format!(
+                "Watchpoint: M[{address}] = {value} (stored by {})",
+                record.pc
+            ))
+        } else if breakpoints && self.breakpoints.contains(&next_pc) {
+            Some(format!("Breakpoint at {next_pc}"))
+        } else if let Some(run) = &mut self.run {
+            if let Some(remaining) = &mut run.remaining {
+                *remaining = remaining.saturating_sub(1);
+            }
+            if cpu.halted {
+                Some(String::from("Program halted"))
+            } else if run.until == Some(next_pc) && run.frame.is_none_or(|frame| sp >= frame) {
+                Some(format!("Reached {next_pc}"))
+            } else if run.returned.is_some_and(|frame| sp > frame) {
+                Some(format!("Returned to {next_pc}"))
+            } else if run.remaining == Some(0) {
+                Some(format!("Stopped at {next_pc}"))
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        if reason.is_some() {
+            self.run = None;
+        }
+        reason
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_console_commands() {
+        assert!(matches!(parse("break 12"), Ok(Command::Break(12))));
+        assert!(matches!(parse("b 0x0c"), Ok(Command::Break(12))));
+        assert!(matches!(parse("delete"), Ok(Command::Delete(None))));
+        assert!(matches!(parse("delete 12"), Ok(Command::Delete(Some(12)))));
+        assert!(matches!(parse("watch 30"), Ok(Command::Watch(30))));
+        assert!(matches!(parse("unwatch 30"), Ok(Command::Unwatch(30))));
+        assert!(matches!(
+            parse("set R1=5"),
+            Ok(Command::Set(EditTarget::Register(1), 5))
+        ));
+        assert!(matches!(
+            parse("set pc = 7"),
+            Ok(Command::Set(EditTarget::Pc, 7))
+        ));
+        assert!(matches!(
+            parse("set M[3]=ADD R1, R1"),
+            Ok(Command::Set(
+                EditTarget::Memory {
+                    address: 3,
+                    instruction: false
+                },
+                0x4101
+            ))
+        ));
+        assert!(matches!(
+            parse("x/8 20"),
+            Ok(Command::Examine {
+                count: 8,
+                address: 20
+            })
+        ));
+        assert!(matches!(
+            parse("x 20"),
+            Ok(Command::Examine {
+                count: 1,
+                address: 20
+            })
+        ));
+        assert!(matches!(parse("run 100"), Ok(Command::Run(Some(100)))));
+        assert!(matches!(parse("continue"), Ok(Command::Run(None))));
+        assert!(matches!(parse("step"), Ok(Command::Step(1))));
+        assert!(matches!(parse("s 3"), Ok(Command::Step(3))));
+        assert!(matches!(parse("next"), Ok(Command::Next)));
+        assert!(matches!(parse("finish"), Ok(Command::Finish)));
+        assert!(matches!(parse("until 7"), Ok(Command::Until(7))));
+        assert!(matches!(parse("info regs"), Ok(Command::InfoRegisters)));
+        assert!(matches!(
+            parse("info registers"),
+            Ok(Command::InfoRegisters)
+        ));
+        assert!(matches!(parse("i b"), Ok(Command::InfoBreakpoints)));
+        assert!(matches!(parse("  help  "), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        let error = |line: &str| parse(line).err().unwrap();
+        assert_eq!(error(""), "Expected a command");
+        assert_eq!(error("jump 3"), "Unknown command jump, try help");
+        assert_eq!(error("watch 300"), "Invalid address 300");
+        assert_eq!(error("run/4 2"), "run doesn't take a /N count");
+        assert_eq!(error("set R1 5"), "Expected set TARGET=VALUE");
+        assert_eq!(error("set R4=5"), "Unknown target R4");
+        assert_eq!(
+            error("info frames"),
+            "Expected info registers or info breakpoints"
+        );
+    }
+
+    #[test]
+    fn info_registers_shows_completed_state() {
+        let mut cpu = CPU::new(&[]);
+        cpu.pc = 9;
+        cpu.sp = 60;
+        *cpu.get_register_mut(1) = 4;
+        let position = Position {
+            next_pc: 6,
+            sp: 62,
+            pending: vec![1],
+        };
+
+        let lines = Debugger::default()
+            .execute(Command::InfoRegisters, &mut cpu, &position)
+            .unwrap();
+        assert!(lines[0].starts_with("PC: 6  SP: 62  "));
+        assert_eq!(lines[1], "R0: 0  R1: 4 (in flight)  R2: 0  R3: 0");
+    }
+}
@@ -0,0 +1,227 @@
+use crate::cli::Options;
+use crate::cpu::{CPU, disassemble};
+use crate::headless;
+use crate::microcode::{ControlUnit, MicrocodeRom};
+use crate::pipeline::Pipeline;
+use crate::trace::{self, Trace, TraceRecord};
+use crate::{build_cpu, read_program};
+
+use std::collections::VecDeque;
+use std::io;
+
+// Rows shown either side of the first difference
+const CONTEXT: usize = 3;
+
+// Give up on runs that never halt
+const MAX_STEPS: u64 = 100_000;
+
+// Exit status of a comparison
+#[derive(Clone, Copy)]
+pub enum Outcome {
+    Identical = 0,
+    Different = 1,
+    CutOff = 3, // Neither run halted within MAX_STEPS
+}
+
+// NOTE: Run being compared
+// Either a live CPU stepped one instruction at a time, pipelined or microcoded as
+// configured, or a trace read from disk
+enum Run {
+    Live(Box<Machine>),
+    Recorded(std::vec::IntoIter<TraceRecord>),
+}
+
+struct Machine {
+    cpu: CPU,
+    pipeline: Option<Pipeline>,
+    control_unit: Option<ControlUnit>,
+    trace: Trace,
+}
+
+impl Run {
+    fn live(options: &Options, program: &[u16], rom: Option<&MicrocodeRom>) -> Run {
+        let cpu = build_cpu(options, program);
+        Run::Live(Box::new(Machine {
+            trace: Trace::new(&cpu),
+            cpu,
+            pipeline: options
+                .pipeline
+                .then(|| Pipeline::new(options.forwarding, options.predictor)),
+            control_unit: rom.map(|rom| ControlUnit::new(rom.clone())),
+        }))
+    }
+
+    fn next(&mut self) -> Option<TraceRecord> {
+        match self {
+            Run::Live(machine) => {
+                let Machine {
+                    cpu,
+                    pipeline,
+                    control_unit,
+                    trace,
+                } = &mut **machine;
+                if cpu.halted {
+                    return None;
+                }
+                headless::step_instruction(cpu, pipeline.as_mut(), control_unit.as_mut(), trace);
+
+                trace.records.pop_back()
+            }
+            Run::Recorded(records) => records.next(),
+        }
+    }
+}
+
+// NOTE: Lockstep comparison
+// Runs the program next to the reference (a binary or a recorded trace) and reports
+// the first instruction where they differ. A trace that dropped its oldest records
+// is compared from its first record on, the program runs up to there unchecked.
+pub fn run(
+    options: &Options,
+    program: &[u16],
+    rom: Option<&MicrocodeRom>,
+    reference: &str,
+) -> io::Result<Outcome> {
+    let (skipped, mut expected) = if is_trace(reference) {
+        let (dropped, records) = trace::read(reference)?;
+        (dropped, Run::Recorded(records.into_iter()))
+    } else {
+        (0, Run::live(options, &read_program(reference)?, rom))
+    };
+    let mut actual = Run::live(options, program, rom);
+
+    if skipped > 0 {
+        println!(
+            "The trace starts at instruction {}, the first {skipped} aren't compared",
+            skipped + 1
+        );
+        for executed in 0..skipped {
+            if actual.next().is_none() {
+                println!("Program halted after {executed} instructions, before the trace starts");
+                return Ok(Outcome::Different);
+            }
+        }
+    }
+
+    let mut history: VecDeque<(Option<TraceRecord>, Option<TraceRecord>)> = VecDeque::new();
+    let mut step = skipped;
+    let differences = loop {
+        step += 1;
+        let pair = (expected.next(), actual.next());
+        if step - skipped > MAX_STEPS && pair.0.is_some() && pair.1.is_some() {
+            println!(
+                "Stopped after {MAX_STEPS} instructions without a difference, \
+                 neither run halted"
+            );
+            return Ok(Outcome::CutOff);
+        }
+
+        let differences = match &pair {
+            (None, None) => {
+                println!("Runs are identical ({} instructions)", step - 1);
+                return Ok(Outcome::Identical);
+            }
+            (Some(_), None) => vec![String::from("program halted, reference continues")],
+            (None, Some(_)) => vec![String::from("reference halted, program continues")],
+            (Some(expected), Some(actual)) => compare(expected, actual),
+        };
+
+        history.push_back(pair);
+        if history.len() > CONTEXT + 1 {
+            history.pop_front();
+        }
+        if !differences.is_empty() {
+            break differences;
+        }
+    };
+
+    let first = step - history.len() as u64 + 1;
+    for _ in 0..CONTEXT {
+        history.push_back((expected.next(), actual.next()));
+    }
+
+    println!("First difference at instruction {step}:");
+    for difference in &differences {
+        println!("  {difference}");
+    }
+    println!();
+    println!(
+        "      │ {:32} │ {:32}",
+        format!("Reference ({reference})"),
+        format!("Program ({})", options.program_path)
+    );
+    for (i, (expected, actual)) in history.iter().enumerate() {
+        let number = first + i as u64;
+        let marker = if number == step { '>' } else { ' ' };
+        if number > step && expected.is_none() && actual.is_none() {
+            break;
+        }
+        println!(
+            "{marker}{number:5}│ {:32} │ {:32}",
+            describe(expected.as_ref()),
+            describe(actual.as_ref())
+        );
+    }
+
+    Ok(Outcome::Different)
+}
+
+// Traces are recognised by their extension, anything else is a binary
+fn is_trace(path: &str) -> bool {
+    let lower = path.to_lowercase();
+    lower.ends_with(".csv") || lower.ends_with(".jsonl")
+}
+
+// Memory only changes through stores, so comparing each instruction's stores finds
+// the first memory difference without tripping over differing program images
+fn compare(want: &TraceRecord, got: &TraceRecord) -> Vec<String> {
+    let mut differences = Vec::new();
+
+    if want.pc != got.pc {
+        differences.push(format!("PC: {} vs {}", want.pc, got.pc));
+    }
+    if want.ir != got.ir {
+        differences.push(format!(
+            "IR: {} vs {}",
+            disassemble(want.ir),
+            disassemble(got.ir)
+        ));
+    }
+    for register in 0..4 {
+        let (want, got) = (want.registers[register], got.registers[register]);
+        if want != got {
+            differences.push(format!("R{register}: {want} vs {got}"));
+        }
+    }
+    if want.memory_writes != got.memory_writes {
+        differences.push(format!(
+            "Stores: {} vs {}",
+            describe_writes(&want.memory_writes),
+            describe_writes(&got.memory_writes)
+        ));
+    }
+    differences
+}
+
+fn describe(record: Option<&TraceRecord>) -> String {
+    match record {
+        Some(record) => format!(
+            "{:2} {:12} {:?}",
+            record.pc,
+            disassemble(record.ir),
+            record.registers
+        ),
+        None => String::from("(halted)"),
+    }
+}
+
+fn describe_writes(writes: &[(u8, u16)]) -> String {
+    if writes.is_empty() {
+        return String::from("none");
+    }
+    writes
+        .iter()
+        .map(|(address, value)| format!("M[{address}]={value}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,105 @@
+use crate::cpu::{CPU, OpCode};
+use crate::syntax::{self, parse_register};
+
+use std::fmt;
+
+// NOTE: Edit target
+// A memory word (data or, on Harvard, instruction memory), a register or the PC
+#[derive(Clone, Copy, PartialEq)]
+pub enum EditTarget {
+    Memory { address: usize, instruction: bool },
+    Register(u8),
+    Pc,
+}
+
+impl EditTarget {
+    pub fn current(&self, cpu: &CPU) -> u16 {
+        match *self {
+            EditTarget::Memory {
+                address,
+                instruction: true,
+            } if let Some(instruction_memory) = &cpu.instruction_memory => {
+                instruction_memory[address]
+            }
+            EditTarget::Memory { address, .. } => cpu.memory[address],
+            EditTarget::Register(register) => cpu.get_register(register),
+            EditTarget::Pc => cpu.pc,
+        }
+    }
+
+    pub fn apply(&self, cpu: &mut CPU, value: u16) {
+        match *self {
+            EditTarget::Memory {
+                address,
+                instruction,
+            } => cpu.poke_memory(address, value, instruction),
+            EditTarget::Register(register) => *cpu.get_register_mut(register) = value,
+            EditTarget::Pc => cpu.pc = value,
+        }
+    }
+}
+
+impl fmt::Display for EditTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EditTarget::Memory {
+                address,
+                instruction: true,
+            } => write!(f, "I[{address}]"),
+            EditTarget::Memory { address, .. } => write!(f, "M[{address}]"),
+            EditTarget::Register(register) => write!(f, "R{register}"),
+            EditTarget::Pc => write!(f, "PC"),
+        }
+    }
+}
+
+// NOTE: Value entry
+// A literal (decimal, 0x hex, 0b binary, 0o octal or a 'c' character, negative values
+// wrap) or an instruction such as "LVAL R2, 10" which is encoded in place. Both follow
+// the assembler's rules.
+pub fn parse_value(text: &str) -> Result<u16, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(String::from("Expected a value"));
+    }
+    if text.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '\'') {
+        return syntax::fit(text, syntax::parse_literal(text)?, u16::BITS);
+    }
+    assemble(text)
+}
+
+// Literal up to max, negative values are stored as two's complement
+pub fn parse_number(text: &str, max: u16) -> Option<u16> {
+    let bits = u16::BITS - max.leading_zeros();
+    let value = syntax::parse_literal(text).ok()?;
+    syntax::fit(text, value, bits).ok()
+}
+
+// Encode one instruction written the way disassemble() prints it. Mnemonics and
+// registers are upper case, as in the assembler.
+pub fn assemble(text: &str) -> Result<u16, String> {
+    let (name, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let opcode = (0..16)
+        .find(|&opcode| OpCode::mnemonic(opcode) == Some(name))
+        .ok_or_else(|| format!("Unknown instruction {name}"))?;
+    let args = syntax::split_operands(args);
+
+    let operand = |arg: &str| -> Result<u16, String> {
+        syntax::fit(arg, syntax::parse_literal(arg)?, u8::BITS)
+    };
+
+    use OpCode::*;
+    let (register, operand) = match (OpCode::u8_to_opcode(opcode), &args[..]) {
+        (Some(HALT | RETI | RET), []) => (0, 0),
+        (Some(JMP | CALL), [target]) => (0, operand(target)?),
+        (Some(ADD | SUB | MOV), [destination, source]) => {
+            (parse_register(destination)?, parse_register(source)? as u16)
+        }
+        (Some(LVAL | LOAD | STORE | JNZ), [destination, value]) => {
+            (parse_register(destination)?, operand(value)?)
+        }
+        _ => return Err(format!("Wrong operands for {name}")),
+    };
+
+    Ok((opcode as u16) << 12 | (register as u16) << 8 | operand)
+}
@@ -0,0 +1,221 @@
+use crate::cpu::CPU;
+use crate::debugger::{self, Debugger, Position};
+use crate::microcode::ControlUnit;
+use crate::pipeline::Pipeline;
+use crate::trace::Trace;
+use crate::watchdog::Watchdog;
+
+// NOTE: Headless runner
+// Runs the program to completion without the TUI and prints the final state.
+// A runaway caught by the watchdog still prints the state, then is the error.
+pub fn run(
+    cpu: &mut CPU,
+    pipeline: Option<&mut Pipeline>,
+    control_unit: Option<&mut ControlUnit>,
+    mut trace: Option<&mut Trace>,
+    watchdog: &mut Watchdog,
+) -> Result<(), String> {
+    let mut runaway = None;
+
+    if let Some(control_unit) = control_unit {
+        while !cpu.halted && runaway.is_none() {
+            control_unit.step(cpu);
+            if let Some(trace) = trace.as_deref_mut() {
+                trace.record_step(cpu);
+            }
+            runaway = watchdog.check(cpu, cpu.fetch_address, cpu.pc);
+        }
+
+        print_summary(cpu);
+        println!("Control: microcoded");
+        return runaway.map_or(Ok(()), Err);
+    }
+
+    match pipeline {
+        Some(pipeline) => {
+            while !cpu.halted && runaway.is_none() {
+                // Only retired instructions are traced
+                if let Some(slot) = pipeline.cycle(cpu) {
+                    if let Some(trace) = trace.as_deref_mut() {
+                        trace.record(
+                            cpu,
+                            slot.pc,
+                            slot.ir,
+                            slot.memory_write().into_iter().collect(),
+                        );
+                    }
+                    let next_pc = pipeline.next_pc().unwrap_or(cpu.pc);
+                    runaway = watchdog.check(cpu, slot.pc, next_pc);
+                }
+            }
+
+            print_summary(cpu);
+            println!("Stalls: {}", pipeline.stalls);
+            println!("Flushed cycles: {}", pipeline.flushed_cycles);
+            println!("Miss stall cycles: {}", pipeline.memory_stalls);
+            println!("Structural stall cycles: {}", pipeline.structural_stalls);
+            print_branch_stats(pipeline);
+        }
+        None => {
+            while !cpu.halted && runaway.is_none() {
+                cpu.step();
+                if let Some(trace) = trace.as_deref_mut() {
+                    trace.record_step(cpu);
+                }
+                runaway = watchdog.check(cpu, cpu.fetch_address, cpu.pc);
+            }
+
+            print_summary(cpu);
+        }
+    }
+    runaway.map_or(Ok(()), Err)
+}
+
+// NOTE: Scripted runs
+// Debugger commands one per line (blank lines and # comments skipped), each echoed
+// with its output. The first bad command stops the script, reported as "LINE: error".
+pub fn run_script(
+    script: &str,
+    cpu: &mut CPU,
+    mut pipeline: Option<&mut Pipeline>,
+    mut control_unit: Option<&mut ControlUnit>,
+    trace: Option<&mut Trace>,
+    watchdog: &mut Watchdog,
+) -> Result<(), String> {
+    // Watchpoints need each instruction's stores, so there is always a trace
+    let mut own_trace = Trace::new(cpu);
+    let trace = trace.unwrap_or(&mut own_trace);
+    let mut debugger = Debugger::default();
+
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        println!("(sim) {line}");
+
+        let output = debugger::parse(line)
+            .and_then(|command| {
+                let position = Position::of(cpu, pipeline.as_deref());
+                debugger.execute(command, cpu, &position)
+            })
+            .map_err(|e| format!("{}: {e}", number + 1))?;
+        for line in output {
+            println!("{line}");
+        }
+        trace.sync_registers(cpu);
+        watchdog.restart();
+
+        while debugger.running() {
+            step_instruction(
+                cpu,
+                pipeline.as_deref_mut(),
+                control_unit.as_deref_mut(),
+                trace,
+            );
+            let position = Position::of(cpu, pipeline.as_deref());
+            let Some(record) = trace.records.back() else {
+                continue;
+            };
+            if cpu.fault.is_none()
+                && let Some(reason) = debugger.after_step(cpu, &position, record)
+            {
+                println!("{reason}");
+            }
+            // A runaway fails the script like a bad command
+            if let Some(reason) = watchdog.check(cpu, record.pc, position.next_pc) {
+                debugger.cancel();
+                print_summary(cpu);
+                return Err(format!("{}: {reason}", number + 1));
+            }
+        }
+    }
+
+    print_summary(cpu);
+    Ok(())
+}
+
+// Run until one instruction completes (or the CPU halts) and record it
+pub fn step_instruction(
+    cpu: &mut CPU,
+    pipeline: Option<&mut Pipeline>,
+    control_unit: Option<&mut ControlUnit>,
+    trace: &mut Trace,
+) {
+    match (pipeline, control_unit) {
+        (Some(pipeline), _) => {
+            while !cpu.halted {
+                if let Some(slot) = pipeline.cycle(cpu) {
+                    let writes = slot.memory_write().into_iter().collect();
+                    trace.record(cpu, slot.pc, slot.ir, writes);
+                    break;
+                }
+            }
+        }
+        (None, Some(control_unit)) => {
+            control_unit.step(cpu);
+            trace.record_step(cpu);
+        }
+        (None, None) => {
+            cpu.step();
+            trace.record_step(cpu);
+        }
+    }
+}
+
+pub fn print_summary(cpu: &CPU) {
+    let registers = cpu.get_all_registers();
+
+    println!("PC: {}  SP: {}  IR: {:016b}", cpu.pc, cpu.sp, cpu.ir);
+    println!(
+        "R0: {}  R1: {}  R2: {}  R3: {}",
+        registers[0], registers[1], registers[2], registers[3]
+    );
+    println!("Instructions: {}", cpu.instructions);
+    println!("Cycles: {}", cpu.cycles);
+    println!("CPI: {:.2}", cpu.cpi());
+
+    if cpu.instruction_memory.is_some() {
+        println!("Architecture: Harvard");
+    }
+
+    let name = if cpu.icache.is_some() {
+        "D-cache"
+    } else {
+        "Cache"
+    };
+    for (cache, name) in [(&cpu.icache, "I-cache"), (&cpu.cache, name)] {
+        if let Some(cache) = cache {
+            println!("{name}: {}", cache.config.describe());
+            println!(
+                "{name} hits: {}  misses: {}  hit rate: {:.1}%  writebacks: {}",
+                cache.hits,
+                cache.misses,
+                cache.hit_rate(),
+                cache.writebacks
+            );
+        }
+    }
+}
+
+fn print_branch_stats(pipeline: &Pipeline) {
+    let predictor = &pipeline.predictor;
+    let total = predictor.total();
+
+    println!(
+        "Predictor: {}  Branches: {}  Accuracy: {:.1}%",
+        predictor.kind.name(),
+        total.executed,
+        total.accuracy()
+    );
+    for (address, stats) in &predictor.stats {
+        println!(
+            "  {:3}: executed {:4}  taken {:4}  correct {:4}  {:5.1}%",
+            address,
+            stats.executed,
+            stats.taken,
+            stats.correct,
+            stats.accuracy()
+        );
+    }
+}
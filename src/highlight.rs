@@ -0,0 +1,57 @@
+use crate::trace::TraceRecord;
+
+use ratatui::style::{Color, Modifier, Style};
+
+// Steps a change stays highlighted, brightest first
+const FADE: [Color; 3] = [Color::LightYellow, Color::Yellow, Color::DarkGray];
+
+// NOTE: Change highlighting
+// Instructions since each register and memory cell was last written,
+// None once the highlight has faded out
+#[derive(Default)]
+pub struct Highlights {
+    registers: [Option<usize>; 4],
+    memory: Vec<Option<usize>>,
+}
+
+impl Highlights {
+    // Age every highlight by one instruction and mark what this one changed
+    pub fn update(&mut self, record: &TraceRecord) {
+        let age = |age: &mut Option<usize>| {
+            *age = age.map(|age| age + 1).filter(|&age| age < FADE.len());
+        };
+        self.registers.iter_mut().for_each(age);
+        self.memory.iter_mut().for_each(age);
+
+        for &(register, _, _) in &record.register_changes {
+            self.registers[register as usize] = Some(0);
+        }
+        for &(address, _) in &record.memory_writes {
+            let address = address as usize;
+            if address >= self.memory.len() {
+                self.memory.resize(address + 1, None);
+            }
+            self.memory[address] = Some(0);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn register(&self, register: usize) -> Style {
+        style(self.registers[register])
+    }
+
+    pub fn memory(&self, address: usize) -> Style {
+        style(self.memory.get(address).copied().flatten())
+    }
+}
+
+fn style(age: Option<usize>) -> Style {
+    match age {
+        Some(0) => Style::default().fg(FADE[0]).add_modifier(Modifier::BOLD),
+        Some(age) => Style::default().fg(FADE[age]),
+        None => Style::default(),
+    }
+}
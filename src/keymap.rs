@@ -0,0 +1,356 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use std::fs;
+use std::io;
+
+// NOTE: Actions
+// Everything a key can do outside the console and edit prompt
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    Step,
+    ToggleMode,
+    Faster,
+    Slower,
+    Turbo,
+    Reset,
+    ToggleBreakpoint,
+    RunToCursor,
+    StepOver,
+    StepOut,
+    Edit,
+    Console,
+    SaveSnapshot,
+    LoadSnapshot,
+    ExportTrace,
+    MicroStep,
+    Datapath,
+    InstructionMemory,
+    Focus,
+    Up,
+    Down,
+    Help,
+    Quit,
+}
+
+// (action, config name, description, default keys), in the order help lists them
+const ACTIONS: [(Action, &str, &str, &[&str]); 23] = [
+    (
+        Action::Step,
+        "step",
+        "Execute one step (step mode)",
+        &["Enter"],
+    ),
+    (
+        Action::ToggleMode,
+        "toggle_mode",
+        "Switch between step and continuous mode",
+        &["t"],
+    ),
+    (
+        Action::Faster,
+        "faster",
+        "Faster continuous mode",
+        &["+", "="],
+    ),
+    (Action::Slower, "slower", "Slower continuous mode", &["-"]),
+    (
+        Action::Turbo,
+        "turbo",
+        "Toggle turbo (as fast as possible)",
+        &["f"],
+    ),
+    (Action::Reset, "reset", "Reset CPU", &["r"]),
+    (
+        Action::ToggleBreakpoint,
+        "breakpoint",
+        "Toggle breakpoint on the selected memory row",
+        &["b"],
+    ),
+    (
+        Action::RunToCursor,
+        "run_to_cursor",
+        "Run until the PC reaches the selected memory row",
+        &["c"],
+    ),
+    (
+        Action::StepOver,
+        "step_over",
+        "Step over a CALL, running the whole subroutine",
+        &["n"],
+    ),
+    (
+        Action::StepOut,
+        "step_out",
+        "Run until the current subroutine returns",
+        &["o"],
+    ),
+    (
+        Action::Edit,
+        "edit",
+        "Edit the selected memory word, register or PC",
+        &["i"],
+    ),
+    (
+        Action::Console,
+        "console",
+        "Open the command console",
+        &[":"],
+    ),
+    (
+        Action::SaveSnapshot,
+        "save_snapshot",
+        "Save snapshot",
+        &["s"],
+    ),
+    (
+        Action::LoadSnapshot,
+        "load_snapshot",
+        "Load snapshot",
+        &["l"],
+    ),
+    (Action::ExportTrace, "export_trace", "Export trace", &["e"]),
+    (
+        Action::MicroStep,
+        "micro_step",
+        "Toggle micro-step mode (not pipelined)",
+        &["u"],
+    ),
+    (
+        Action::Datapath,
+        "datapath",
+        "Switch CPU Status to the datapath diagram",
+        &["d"],
+    ),
+    (
+        Action::InstructionMemory,
+        "instruction_memory",
+        "Switch memory view between data and instructions (Harvard)",
+        &["m"],
+    ),
+    (
+        Action::Focus,
+        "focus",
+        "Switch focus between Memory view, CPU Status and Register Logs",
+        &["Tab"],
+    ),
+    (
+        Action::Up,
+        "up",
+        "Scroll or select up in the focused pane",
+        &["Up"],
+    ),
+    (
+        Action::Down,
+        "down",
+        "Scroll or select down in the focused pane",
+        &["Down"],
+    ),
+    (Action::Help, "help", "Show this help", &["?"]),
+    (Action::Quit, "quit", "Quit", &["q"]),
+];
+
+// Named keys, anything else is a single character. # and , have names since the
+// config file uses them for comments and between keys.
+const KEY_NAMES: [(&str, KeyCode); 14] = [
+    ("Enter", KeyCode::Enter),
+    ("Tab", KeyCode::Tab),
+    ("Space", KeyCode::Char(' ')),
+    ("Backspace", KeyCode::Backspace),
+    ("Esc", KeyCode::Esc),
+    ("Up", KeyCode::Up),
+    ("Down", KeyCode::Down),
+    ("Left", KeyCode::Left),
+    ("Right", KeyCode::Right),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("Delete", KeyCode::Delete),
+    ("Hash", KeyCode::Char('#')),
+    ("Comma", KeyCode::Char(',')),
+];
+
+pub fn parse_key(name: &str) -> Option<KeyCode> {
+    if let Some(&(_, code)) = KEY_NAMES
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+    {
+        return Some(code);
+    }
+    if let Some(number) = name.strip_prefix(['F', 'f'])
+        && let Ok(number) = number.parse::<u8>()
+        && (1..=12).contains(&number)
+    {
+        return Some(KeyCode::F(number));
+    }
+
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(KeyCode::Char(c)),
+        _ => None,
+    }
+}
+
+pub fn key_name(code: KeyCode) -> String {
+    match KEY_NAMES.iter().find(|(_, key)| *key == code) {
+        Some((name, _)) => name.to_string(),
+        None => match code {
+            KeyCode::Char(c) => c.to_string(),
+            KeyCode::F(number) => format!("F{number}"),
+            _ => String::from("?"),
+        },
+    }
+}
+
+// NOTE: Key map
+// Defaults overridden by a config file of "action = key, key" lines (# comments).
+// Keys given in the file are taken away from any action that had them by default.
+pub struct KeyMap {
+    pub bindings: Vec<(KeyCode, Action)>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        let bindings = ACTIONS
+            .iter()
+            .flat_map(|&(action, _, _, keys)| {
+                keys.iter()
+                    .map(move |key| (parse_key(key).expect("default key"), action))
+            })
+            .collect();
+        Self { bindings }
+    }
+}
+
+impl KeyMap {
+    pub fn load(path: &str) -> io::Result<KeyMap> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{path}:{e}")))
+    }
+
+    pub fn parse(text: &str) -> Result<KeyMap, String> {
+        let mut configured: Vec<(KeyCode, Action)> = Vec::new();
+        let mut rebound: Vec<Action> = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("{}: {message}", number + 1);
+
+            let (name, keys) = line
+                .split_once('=')
+                .ok_or_else(|| error(format!("Expected action = key, got {line}")))?;
+            let name = name.trim();
+            let &(action, _, _, _) = ACTIONS
+                .iter()
+                .find(|(_, action_name, _, _)| *action_name == name)
+                .ok_or_else(|| error(format!("Unknown action {name}")))?;
+
+            rebound.push(action);
+            for key in keys.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+                let code = parse_key(key).ok_or_else(|| error(format!("Unknown key {key}")))?;
+                if let Some(&(_, other)) = configured.iter().find(|&&(bound, _)| bound == code)
+                    && other != action
+                {
+                    return Err(error(format!(
+                        "{key} is already bound to {}",
+                        action_name(other)
+                    )));
+                }
+                configured.push((code, action));
+            }
+        }
+
+        // Defaults survive for actions the file doesn't mention and keys it doesn't use
+        let mut bindings: Vec<(KeyCode, Action)> = KeyMap::default()
+            .bindings
+            .into_iter()
+            .filter(|(code, action)| {
+                !rebound.contains(action) && !configured.iter().any(|(bound, _)| bound == code)
+            })
+            .collect();
+        bindings.extend(configured);
+        Ok(KeyMap { bindings })
+    }
+
+    // Bindings are plain keys, Ctrl or Alt with a key is never an action. Shift
+    // is allowed since it's how many characters are typed.
+    pub fn action(&self, key: KeyEvent) -> Option<Action> {
+        if !(key.modifiers - KeyModifiers::SHIFT).is_empty() {
+            return None;
+        }
+        self.bindings
+            .iter()
+            .find(|(bound, _)| *bound == key.code)
+            .map(|&(_, action)| action)
+    }
+
+    pub fn keys(&self, action: Action) -> Vec<String> {
+        self.bindings
+            .iter()
+            .filter(|(_, bound)| *bound == action)
+            .map(|&(code, _)| key_name(code))
+            .collect()
+    }
+
+    // (keys, description) for every action, unbound ones included
+    pub fn help(&self) -> Vec<(String, &'static str)> {
+        ACTIONS
+            .iter()
+            .map(|&(action, _, description, _)| (self.keys(action).join(" "), description))
+            .collect()
+    }
+}
+
+pub fn action_name(action: Action) -> &'static str {
+    ACTIONS
+        .iter()
+        .find(|(candidate, _, _, _)| *candidate == action)
+        .map_or("", |&(_, name, _, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        KeyMap::parse(text).err().unwrap()
+    }
+
+    #[test]
+    fn reports_bad_lines_with_their_number() {
+        assert_eq!(error("# keys\nstep"), "2: Expected action = key, got step");
+        assert_eq!(error("jump = j"), "1: Unknown action jump");
+        assert_eq!(error("step = Enter, Ctrl"), "1: Unknown key Ctrl");
+        assert_eq!(error("step = F13"), "1: Unknown key F13");
+        assert_eq!(
+            error("step = x\nreset = x"),
+            "2: x is already bound to step"
+        );
+    }
+
+    #[test]
+    fn file_bindings_replace_defaults() {
+        let keymap = KeyMap::parse("step = Space, Hash # comment\nquit = Comma").unwrap();
+        let plain = |code| KeyEvent::new(code, KeyModifiers::NONE);
+
+        assert!(keymap.action(plain(KeyCode::Char(' '))) == Some(Action::Step));
+        assert!(keymap.action(plain(KeyCode::Char('#'))) == Some(Action::Step));
+        assert!(keymap.action(plain(KeyCode::Char(','))) == Some(Action::Quit));
+        // Listed actions lose their defaults, the others keep them
+        assert!(keymap.action(plain(KeyCode::Enter)).is_none());
+        assert!(keymap.action(plain(KeyCode::Char('q'))).is_none());
+        assert!(keymap.action(plain(KeyCode::Char('r'))) == Some(Action::Reset));
+    }
+
+    #[test]
+    fn keys_with_ctrl_or_alt_are_not_actions() {
+        let keymap = KeyMap::default();
+        let key = |code, modifiers| keymap.action(KeyEvent::new(code, modifiers));
+
+        assert!(key(KeyCode::Char('c'), KeyModifiers::NONE) == Some(Action::RunToCursor));
+        assert!(key(KeyCode::Char('c'), KeyModifiers::CONTROL).is_none());
+        assert!(key(KeyCode::Char('q'), KeyModifiers::ALT).is_none());
+        assert!(key(KeyCode::Char('?'), KeyModifiers::SHIFT) == Some(Action::Help));
+    }
+}
@@ -1,28 +1,226 @@
+#![allow(clippy::upper_case_acronyms)]
+
 mod app;
+mod cache;
+mod cli;
+mod console;
 mod cpu;
+mod datapath;
+mod debugger;
+mod diff;
+mod edit;
+mod headless;
+mod highlight;
+mod keymap;
+mod micro;
+mod microcode;
+mod pipeline;
+mod predictor;
+mod snapshot;
+mod syntax;
+mod timer;
+mod trace;
+mod watchdog;
 
 use crate::app::App;
+use crate::cache::Cache;
+use crate::cli::Options;
 use crate::cpu::CPU;
+use crate::keymap::KeyMap;
+use crate::microcode::{ControlUnit, DEFAULT_ROM, MicrocodeRom};
+use crate::pipeline::Pipeline;
+use crate::trace::Trace;
+use crate::watchdog::Watchdog;
 
 use std::{fs, io};
 
 fn main() -> io::Result<()> {
-    let mut terminal = ratatui::init();
+    let options = match cli::parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
 
-    let program_bin = fs::read("program.bin").unwrap_or_default();
+    if options.dump_microcode {
+        print!("{DEFAULT_ROM}");
+        return Ok(());
+    }
 
-    let program: Vec<u16> = program_bin
-        .chunks_exact(2)
-        .map(|chunk| u16::from_le_bytes([chunk[1], chunk[0]]))
-        .collect();
+    let rom = match &options.microcode_path {
+        Some(path) => MicrocodeRom::parse(&fs::read_to_string(path)?),
+        None => Ok(MicrocodeRom::default_rom()),
+    };
+    let mut control_unit = match rom {
+        Ok(rom) => options.microcoded.then(|| ControlUnit::new(rom)),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+
+    // A missing program starts with empty memory, one that doesn't fit is an error
+    let program = match read_program(&options.program_path) {
+        Ok(program) => program,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+        Err(_) => Vec::new(),
+    };
+    let mut cpu = build_cpu(&options, &program);
+
+    if let Some(reference) = &options.diff {
+        let rom = control_unit.as_ref().map(|control_unit| &control_unit.rom);
+        match diff::run(&options, &program, rom, reference) {
+            Ok(outcome) => std::process::exit(outcome as i32),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if let Some(path) = &options.load_snapshot
+        && let Err(e) = snapshot::load(&mut cpu, path)
+    {
+        eprintln!("{e}");
+        std::process::exit(2);
+    }
+
+    let mut pipeline = options
+        .pipeline
+        .then(|| Pipeline::new(options.forwarding, options.predictor));
+
+    // A snapshot's memory isn't the program, so where it ends is unknown
+    let program_end = match options.load_snapshot {
+        Some(_) => 0,
+        None => program.len(),
+    };
+    let mut watchdog = Watchdog::new(options.max_instructions, program_end);
+
+    if options.headless {
+        let mut trace = options.trace_path.as_ref().map(|_| Trace::new(&cpu));
+        // Script errors are usage errors (2), a runaway program fails the run (1)
+        let failure = match &options.script {
+            Some(path) => {
+                let script = fs::read_to_string(path)?;
+                headless::run_script(
+                    &script,
+                    &mut cpu,
+                    pipeline.as_mut(),
+                    control_unit.as_mut(),
+                    trace.as_mut(),
+                    &mut watchdog,
+                )
+                .err()
+                .map(|e| (format!("{path}:{e}"), 2))
+            }
+            None => headless::run(
+                &mut cpu,
+                pipeline.as_mut(),
+                control_unit.as_mut(),
+                trace.as_mut(),
+                &mut watchdog,
+            )
+            .err()
+            .map(|e| (e, 1)),
+        };
+        // The final state is saved either way, it's what a runaway needs looking at
+        if let Some(path) = &options.save_snapshot {
+            snapshot::save(&cpu, path)?;
+        }
+        if let (Some(trace), Some(path)) = (&trace, &options.trace_path) {
+            trace.export(path)?;
+            if let Some(warning) = trace.truncated() {
+                eprintln!("{path}: {warning}");
+            }
+        }
+        if let Some((message, code)) = failure {
+            eprintln!("{message}");
+            std::process::exit(code);
+        }
+        return Ok(());
+    }
+
+    let keymap = match &options.keys_path {
+        Some(path) => match KeyMap::load(path) {
+            Ok(keymap) => keymap,
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(2);
+            }
+        },
+        None => KeyMap::default(),
+    };
+
+    let mut terminal = ratatui::init();
 
     let mut app = App {
-        cpu: CPU::new(&program),
+        trace: Trace::new(&cpu),
+        cpu,
         program,
+        pipeline,
+        control_unit,
+        trace_path: options
+            .trace_path
+            .clone()
+            .unwrap_or_else(|| String::from("trace.jsonl")),
+        snapshot_path: options.snapshot_path,
+        keymap,
+        watchdog,
         ..Default::default()
     };
 
     let app_result = app.run(&mut terminal);
     ratatui::restore();
+    if let Some(path) = &options.save_snapshot {
+        snapshot::save(&app.cpu, path)?;
+    }
+    if options.trace_path.is_some() {
+        app.trace.export(&app.trace_path)?;
+        if let Some(warning) = app.trace.truncated() {
+            eprintln!("{}: {warning}", app.trace_path);
+        }
+    }
     app_result
 }
+
+// program.bin holds big-endian 16-bit words
+fn read_program(path: &str) -> io::Result<Vec<u16>> {
+    let program_bin = fs::read(path)?;
+
+    let program: Vec<u16> = program_bin
+        .chunks_exact(2)
+        .map(|chunk| u16::from_le_bytes([chunk[1], chunk[0]]))
+        .collect();
+    // It has to fit in the 64 words of memory
+    if program.len() > 64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{path}: program is {} words, memory holds 64",
+                program.len()
+            ),
+        ));
+    }
+    Ok(program)
+}
+
+// CPU with the configuration from the command line applied
+fn build_cpu(options: &Options, program: &[u16]) -> CPU {
+    let mut cpu = CPU::new(program);
+    for &(opcode, cycles) in &options.cycle_costs {
+        cpu.cycle_costs[opcode as usize] = cycles;
+    }
+    cpu.cache = options.cache.map(Cache::new);
+    if options.harvard {
+        cpu.split_memories();
+    }
+    // Separate memories always get separate caches
+    if options.harvard || options.split_cache {
+        cpu.icache = options.cache.map(Cache::new);
+    }
+    cpu
+}
@@ -0,0 +1,260 @@
+use crate::cpu::{CPU, OpCode, decode_word};
+
+// NOTE: Micro-operations
+// Register transfers that make up an instruction, in RTL order
+#[derive(Clone, Copy, PartialEq)]
+pub enum MicroOp {
+    PcToMar,         // MAR ← PC
+    ReadInstruction, // MDR ← M[MAR] (instruction memory on Harvard)
+    IncrementPc,     // PC ← PC + 1
+    MdrToIr,         // IR ← MDR
+    Decode,          // Select the execute sequence from IR's opcode
+    OperandToMar,    // MAR ← IR[7:0]
+    ReadMemory,      // MDR ← M[MAR]
+    WriteMemory,     // M[MAR] ← MDR
+    OperandToReg,    // Rd ← IR[7:0]
+    MdrToReg,        // Rd ← MDR
+    RegToMdr,        // MDR ← Rd
+    RegToAluA,       // A ← Rd
+    RegToAluB,       // B ← Rs
+    AluAdd,          // OUT ← A + B
+    AluSub,          // OUT ← A - B
+    AluPassB,        // OUT ← B
+    AluInc,          // OUT ← A + 1
+    AluDec,          // OUT ← A - 1
+    AluAnd,          // OUT ← A & B
+    AluOr,           // OUT ← A | B
+    AluXor,          // OUT ← A ^ B
+    AluShl,          // OUT ← A << 1
+    AluShr,          // OUT ← A >> 1
+    AluOutToReg,     // Rd ← OUT
+    OperandToPc,     // PC ← IR[7:0]
+    BranchIfNonZero, // if A ≠ 0: PC ← IR[7:0]
+    BranchIfZero,    // if A = 0: PC ← IR[7:0]
+    EpcToPc,         // PC ← EPC, leave interrupt
+    SpToMar,         // MAR ← SP
+    SpDec,           // SP ← SP - 1
+    SpInc,           // SP ← SP + 1
+    PcToMdr,         // MDR ← PC
+    MdrToPc,         // PC ← MDR
+    Halt,
+}
+
+use MicroOp::*;
+
+// Every micro-op with the name used in microcode ROM files
+const NAMES: [(MicroOp, &str); 34] = [
+    (PcToMar, "PcToMar"),
+    (ReadInstruction, "ReadInstruction"),
+    (IncrementPc, "IncrementPc"),
+    (MdrToIr, "MdrToIr"),
+    (Decode, "Decode"),
+    (OperandToMar, "OperandToMar"),
+    (ReadMemory, "ReadMemory"),
+    (WriteMemory, "WriteMemory"),
+    (OperandToReg, "OperandToReg"),
+    (MdrToReg, "MdrToReg"),
+    (RegToMdr, "RegToMdr"),
+    (RegToAluA, "RegToAluA"),
+    (RegToAluB, "RegToAluB"),
+    (AluAdd, "AluAdd"),
+    (AluSub, "AluSub"),
+    (AluPassB, "AluPassB"),
+    (AluInc, "AluInc"),
+    (AluDec, "AluDec"),
+    (AluAnd, "AluAnd"),
+    (AluOr, "AluOr"),
+    (AluXor, "AluXor"),
+    (AluShl, "AluShl"),
+    (AluShr, "AluShr"),
+    (AluOutToReg, "AluOutToReg"),
+    (OperandToPc, "OperandToPc"),
+    (BranchIfNonZero, "BranchIfNonZero"),
+    (BranchIfZero, "BranchIfZero"),
+    (EpcToPc, "EpcToPc"),
+    (SpToMar, "SpToMar"),
+    (SpDec, "SpDec"),
+    (SpInc, "SpInc"),
+    (PcToMdr, "PcToMdr"),
+    (MdrToPc, "MdrToPc"),
+    (Halt, "Halt"),
+];
+
+impl MicroOp {
+    pub fn from_name(name: &str) -> Option<MicroOp> {
+        NAMES
+            .iter()
+            .find(|(_, op_name)| *op_name == name)
+            .map(|(op, _)| *op)
+    }
+}
+
+pub const FETCH_SEQUENCE: [MicroOp; 5] = [PcToMar, ReadInstruction, IncrementPc, MdrToIr, Decode];
+
+// Execute phase of each instruction, run after FETCH_SEQUENCE. None for an
+// illegal opcode.
+pub fn execute_sequence(opcode: u8) -> Option<Vec<MicroOp>> {
+    let opcode = OpCode::u8_to_opcode(opcode)?;
+
+    Some(match opcode {
+        OpCode::HALT => vec![Halt],
+        OpCode::LVAL => vec![OperandToReg],
+        OpCode::LOAD => vec![OperandToMar, ReadMemory, MdrToReg],
+        OpCode::STORE => vec![OperandToMar, RegToMdr, WriteMemory],
+        OpCode::ADD => vec![RegToAluA, RegToAluB, AluAdd, AluOutToReg],
+        OpCode::SUB => vec![RegToAluA, RegToAluB, AluSub, AluOutToReg],
+        OpCode::JMP => vec![OperandToPc],
+        OpCode::MOV => vec![RegToAluB, AluPassB, AluOutToReg],
+        OpCode::RETI => vec![EpcToPc],
+        OpCode::JNZ => vec![RegToAluA, BranchIfNonZero],
+        OpCode::CALL => vec![SpDec, SpToMar, PcToMdr, WriteMemory, OperandToPc],
+        OpCode::RET => vec![SpToMar, ReadMemory, SpInc, MdrToPc],
+    })
+}
+
+// Perform one register transfer on the CPU
+pub fn apply(cpu: &mut CPU, op: MicroOp) {
+    let (_, register, operand) = decode_word(cpu.ir);
+
+    match op {
+        PcToMar => cpu.mar = cpu.pc,
+        ReadInstruction => cpu.mdr = cpu.fetch_word(cpu.mar),
+        IncrementPc => cpu.pc = cpu.pc.wrapping_add(1),
+        MdrToIr => cpu.ir = cpu.mdr,
+        Decode => {}
+        OperandToMar => cpu.mar = operand as u16,
+        ReadMemory => cpu.mdr = cpu.read_memory(cpu.mar as u8),
+        WriteMemory => cpu.write_memory(cpu.mar as u8, cpu.mdr),
+        OperandToReg => *cpu.get_register_mut(register) = operand as u16,
+        MdrToReg => *cpu.get_register_mut(register) = cpu.mdr,
+        RegToMdr => cpu.mdr = cpu.get_register(register),
+        RegToAluA => cpu.alu_a = cpu.get_register(register),
+        RegToAluB => cpu.alu_b = cpu.get_register(operand),
+        AluAdd => cpu.alu_out = cpu.alu_a.wrapping_add(cpu.alu_b),
+        AluSub => cpu.alu_out = cpu.alu_a.wrapping_sub(cpu.alu_b),
+        AluPassB => cpu.alu_out = cpu.alu_b,
+        AluInc => cpu.alu_out = cpu.alu_a.wrapping_add(1),
+        AluDec => cpu.alu_out = cpu.alu_a.wrapping_sub(1),
+        AluAnd => cpu.alu_out = cpu.alu_a & cpu.alu_b,
+        AluOr => cpu.alu_out = cpu.alu_a | cpu.alu_b,
+        AluXor => cpu.alu_out = cpu.alu_a ^ cpu.alu_b,
+        AluShl => cpu.alu_out = cpu.alu_a << 1,
+        AluShr => cpu.alu_out = cpu.alu_a >> 1,
+        AluOutToReg => *cpu.get_register_mut(register) = cpu.alu_out,
+        OperandToPc => cpu.pc = operand as u16,
+        BranchIfNonZero => {
+            if cpu.alu_a != 0 {
+                cpu.pc = operand as u16;
+            }
+        }
+        BranchIfZero => {
+            if cpu.alu_a == 0 {
+                cpu.pc = operand as u16;
+            }
+        }
+        EpcToPc => {
+            cpu.pc = cpu.epc;
+            cpu.in_interrupt = false;
+        }
+        SpToMar => cpu.mar = cpu.sp,
+        SpDec => cpu.sp = cpu.sp.wrapping_sub(1),
+        SpInc => cpu.sp = cpu.sp.wrapping_add(1),
+        PcToMdr => cpu.mdr = cpu.pc,
+        MdrToPc => cpu.pc = cpu.mdr,
+        Halt => cpu.halted = true,
+    }
+}
+
+// RTL text for a micro-op with the instruction's registers filled in
+pub fn describe(op: MicroOp, ir: u16) -> String {
+    let (_, register, operand) = decode_word(ir);
+    let rd = format!("R{}", register & 0b11);
+    let rs = format!("R{}", operand & 0b11);
+
+    match op {
+        PcToMar => String::from("MAR ← PC"),
+        ReadInstruction | ReadMemory => String::from("MDR ← M[MAR]"),
+        IncrementPc => String::from("PC ← PC + 1"),
+        MdrToIr => String::from("IR ← MDR"),
+        Decode => String::from("Decode IR"),
+        OperandToMar => format!("MAR ← {operand}"),
+        WriteMemory => String::from("M[MAR] ← MDR"),
+        OperandToReg => format!("{rd} ← {operand}"),
+        MdrToReg => format!("{rd} ← MDR"),
+        RegToMdr => format!("MDR ← {rd}"),
+        RegToAluA => format!("A ← {rd}"),
+        RegToAluB => format!("B ← {rs}"),
+        AluAdd => String::from("OUT ← A + B"),
+        AluSub => String::from("OUT ← A - B"),
+        AluPassB => String::from("OUT ← B"),
+        AluInc => String::from("OUT ← A + 1"),
+        AluDec => String::from("OUT ← A - 1"),
+        AluAnd => String::from("OUT ← A & B"),
+        AluOr => String::from("OUT ← A | B"),
+        AluXor => String::from("OUT ← A ^ B"),
+        AluShl => String::from("OUT ← A << 1"),
+        AluShr => String::from("OUT ← A >> 1"),
+        AluOutToReg => format!("{rd} ← OUT"),
+        OperandToPc => format!("PC ← {operand}"),
+        BranchIfNonZero => format!("if A ≠ 0: PC ← {operand}"),
+        BranchIfZero => format!("if A = 0: PC ← {operand}"),
+        EpcToPc => String::from("PC ← EPC"),
+        SpToMar => String::from("MAR ← SP"),
+        SpDec => String::from("SP ← SP - 1"),
+        SpInc => String::from("SP ← SP + 1"),
+        PcToMdr => String::from("MDR ← PC"),
+        MdrToPc => String::from("PC ← MDR"),
+        Halt => String::from("Halt"),
+    }
+}
+
+// NOTE: Micro-step sequencer
+// Runs an instruction one register transfer at a time
+#[derive(Default)]
+pub struct MicroSequencer {
+    pub ops: Vec<MicroOp>,
+    pub next: usize, // Index of the next micro-op to run
+}
+
+impl MicroSequencer {
+    // Part way through an instruction
+    pub fn in_progress(&self) -> bool {
+        self.next > 0 && self.next < self.ops.len()
+    }
+
+    // Runs a single micro-op, returns true once the instruction is complete
+    pub fn micro_step(&mut self, cpu: &mut CPU) -> bool {
+        if cpu.halted {
+            return false;
+        }
+
+        if !self.in_progress() {
+            cpu.begin_instruction();
+            self.ops = FETCH_SEQUENCE.to_vec();
+            self.next = 0;
+        }
+
+        let op = self.ops[self.next];
+        self.next += 1;
+        apply(cpu, op);
+
+        let (opcode, _, _) = cpu.decode();
+        if op == Decode {
+            match execute_sequence(opcode) {
+                Some(ops) => self.ops.extend(ops),
+                None => cpu.illegal_instruction(cpu.fetch_address, cpu.ir),
+            }
+        }
+
+        if self.next == self.ops.len() {
+            cpu.finish_instruction(opcode);
+            return true;
+        }
+        false
+    }
+
+    // Index of the micro-op that ran last
+    pub fn active(&self) -> Option<usize> {
+        self.next.checked_sub(1)
+    }
+}
@@ -0,0 +1,320 @@
+use crate::cpu::CPU;
+use crate::micro::{self, MicroOp};
+
+// Size of the control store in microinstructions
+pub const ROM_SIZE: usize = 256;
+
+// Stop runaway microcode that never returns to fetch
+const MAX_MICRO_STEPS: usize = 1024;
+
+// NOTE: Default microcode ROM
+// Implements the same instruction set as the hard-wired CPU::execute
+pub const DEFAULT_ROM: &str = "\
+; Microcode ROM
+; ADDRESS: MICRO-OPS... [-> end | dispatch | goto ADDRESS]
+; Without a sequencing part the next address is ADDRESS + 1.
+; Dispatch table: .op OPCODE MNEMONIC ADDRESS
+
+.op 0 HALT 16
+.op 1 LVAL 20
+.op 2 LOAD 24
+.op 3 STORE 28
+.op 4 ADD 32
+.op 5 SUB 36
+.op 6 JMP 40
+.op 7 MOV 44
+.op 8 RETI 48
+.op 9 JNZ 52
+.op 10 CALL 56
+.op 11 RET 60
+
+; Fetch
+0: PcToMar
+1: ReadInstruction IncrementPc
+2: MdrToIr -> dispatch
+
+; HALT
+16: Halt -> end
+
+; LVAL
+20: OperandToReg -> end
+
+; LOAD
+24: OperandToMar
+25: ReadMemory
+26: MdrToReg -> end
+
+; STORE
+28: OperandToMar RegToMdr
+29: WriteMemory -> end
+
+; ADD
+32: RegToAluA RegToAluB
+33: AluAdd
+34: AluOutToReg -> end
+
+; SUB
+36: RegToAluA RegToAluB
+37: AluSub
+38: AluOutToReg -> end
+
+; JMP
+40: OperandToPc -> end
+
+; MOV
+44: RegToAluB
+45: AluPassB
+46: AluOutToReg -> end
+
+; RETI
+48: EpcToPc -> end
+
+; JNZ
+52: RegToAluA
+53: BranchIfNonZero -> end
+
+; CALL
+56: SpDec PcToMdr
+57: SpToMar
+58: WriteMemory OperandToPc -> end
+
+; RET
+60: SpToMar
+61: ReadMemory SpInc
+62: MdrToPc -> end
+";
+
+// How the next microinstruction is chosen
+#[derive(Clone, Copy)]
+pub enum Sequence {
+    Next,
+    Goto(usize),
+    Dispatch, // Jump to the routine for IR's opcode
+    End,      // Instruction done, back to fetch at address 0
+}
+
+#[derive(Clone)]
+pub struct MicroInstruction {
+    pub ops: Vec<MicroOp>,
+    pub sequence: Sequence,
+}
+
+// NOTE: Control store
+#[derive(Clone)]
+pub struct MicrocodeRom {
+    pub words: Vec<Option<MicroInstruction>>,
+    pub dispatch: [Option<usize>; 16],
+    pub mnemonics: [Option<String>; 16],
+}
+
+impl MicrocodeRom {
+    pub fn parse(source: &str) -> Result<MicrocodeRom, String> {
+        let mut rom = MicrocodeRom {
+            words: vec![None; ROM_SIZE],
+            dispatch: [None; 16],
+            mnemonics: Default::default(),
+        };
+        // Line each word and dispatch entry came from, for the checks at the end
+        let mut word_lines = [0; ROM_SIZE];
+        let mut dispatch_lines = [0; 16];
+
+        for (number, line) in source.lines().enumerate() {
+            let error = |message: String| format!("Microcode line {}: {message}", number + 1);
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(directive) = line.strip_prefix(".op") {
+                let fields: Vec<&str> = directive.split_whitespace().collect();
+                let [opcode, mnemonic, address] = fields[..] else {
+                    return Err(error(String::from("Expected .op OPCODE MNEMONIC ADDRESS")));
+                };
+                let opcode = parse_number(opcode, 16).map_err(error)?;
+                let address = parse_number(address, ROM_SIZE).map_err(error)?;
+
+                rom.dispatch[opcode] = Some(address);
+                rom.mnemonics[opcode] = Some(mnemonic.to_uppercase());
+                dispatch_lines[opcode] = number + 1;
+                continue;
+            }
+
+            let (address, body) = line
+                .split_once(':')
+                .ok_or_else(|| error(String::from("Expected ADDRESS: MICRO-OPS")))?;
+            let address = parse_number(address.trim(), ROM_SIZE).map_err(error)?;
+
+            let (ops, sequence) = match body.split_once("->") {
+                Some((ops, sequence)) => (ops, parse_sequence(sequence.trim()).map_err(error)?),
+                None => (body, Sequence::Next),
+            };
+            let ops = ops
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    MicroOp::from_name(name)
+                        .ok_or_else(|| error(format!("Unknown micro-op {name}")))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if rom.words[address].is_some() {
+                return Err(error(format!("Address {address} defined twice")));
+            }
+            rom.words[address] = Some(MicroInstruction { ops, sequence });
+            word_lines[address] = number + 1;
+        }
+
+        if rom.words[0].is_none() {
+            return Err(String::from("Microcode has no fetch routine at address 0"));
+        }
+
+        // Every way out of a microinstruction has to lead to another one
+        let missing = |address: usize| match rom.words.get(address) {
+            Some(Some(_)) => None,
+            Some(None) => Some(format!("there is no microinstruction at {address}")),
+            None => Some(format!("{address} is past the end of the ROM")),
+        };
+        for (address, word) in rom.words.iter().enumerate() {
+            let target = match word.as_ref().map(|word| word.sequence) {
+                Some(Sequence::Next) => address + 1,
+                Some(Sequence::Goto(target)) => target,
+                _ => continue,
+            };
+            if let Some(problem) = missing(target) {
+                return Err(format!(
+                    "Microcode line {}: Address {address} continues at {target}, but {problem}",
+                    word_lines[address]
+                ));
+            }
+        }
+        for (opcode, target) in rom.dispatch.iter().enumerate() {
+            if let Some(target) = *target
+                && let Some(problem) = missing(target)
+            {
+                return Err(format!(
+                    "Microcode line {}: Opcode {opcode} dispatches to {target}, but {problem}",
+                    dispatch_lines[opcode]
+                ));
+            }
+        }
+        Ok(rom)
+    }
+
+    pub fn default_rom() -> MicrocodeRom {
+        MicrocodeRom::parse(DEFAULT_ROM).expect("Default microcode ROM is valid")
+    }
+}
+
+// Helper functions for parsing
+fn parse_number(text: &str, limit: usize) -> Result<usize, String> {
+    match text.parse::<usize>() {
+        Ok(value) if value < limit => Ok(value),
+        _ => Err(format!("Expected a number below {limit}, got {text}")),
+    }
+}
+
+fn parse_sequence(text: &str) -> Result<Sequence, String> {
+    match text.split_whitespace().collect::<Vec<_>>()[..] {
+        ["next"] => Ok(Sequence::Next),
+        ["end"] => Ok(Sequence::End),
+        ["dispatch"] => Ok(Sequence::Dispatch),
+        ["goto", address] => Ok(Sequence::Goto(parse_number(address, ROM_SIZE)?)),
+        _ => Err(format!("Unknown sequencing {text}")),
+    }
+}
+
+// NOTE: Microprogrammed control unit
+// Alternative to the hard-wired CPU::execute, every microinstruction takes one cycle
+pub struct ControlUnit {
+    pub rom: MicrocodeRom,
+    pub upc: usize,        // Micro program counter
+    pub trace: Vec<usize>, // Addresses run so far for the current instruction
+}
+
+impl ControlUnit {
+    pub fn new(rom: MicrocodeRom) -> Self {
+        Self {
+            rom,
+            upc: 0,
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.upc = 0;
+        self.trace.clear();
+    }
+
+    // Part way through an instruction
+    pub fn in_progress(&self) -> bool {
+        self.upc != 0
+    }
+
+    // Runs a single microinstruction, returns true once the instruction is complete.
+    // parse() makes sure every address reached is defined, an opcode without a .op
+    // or microcode that never gets back to fetch stops the CPU with a fault.
+    pub fn micro_step(&mut self, cpu: &mut CPU) -> bool {
+        if cpu.halted {
+            return false;
+        }
+
+        if self.upc == 0 {
+            cpu.begin_instruction();
+            self.trace.clear();
+        }
+
+        let address = self.upc;
+        let word = self.rom.words[address]
+            .clone()
+            .expect("parse checks every microcode address");
+        self.trace.push(address);
+
+        for &op in &word.ops {
+            micro::apply(cpu, op);
+        }
+
+        self.upc = match word.sequence {
+            Sequence::Next => address + 1,
+            Sequence::Goto(target) => target,
+            Sequence::Dispatch => {
+                let opcode = (cpu.ir >> 12) as usize;
+                self.rom.dispatch[opcode].unwrap_or_else(|| {
+                    cpu.trap(format!(
+                        "Illegal instruction 0x{:04X} at {}, the microcode has no .op {opcode}",
+                        cpu.ir, cpu.fetch_address
+                    ));
+                    0
+                })
+            }
+            Sequence::End => 0,
+        };
+
+        if self.upc != 0 && self.trace.len() >= MAX_MICRO_STEPS {
+            cpu.trap(format!(
+                "Microcode ran {MAX_MICRO_STEPS} steps without returning to fetch, \
+                 stopped at μPC {}",
+                self.upc
+            ));
+            self.upc = 0;
+        }
+        if self.upc == 0 {
+            cpu.account_cycles(self.trace.len() as u64);
+            return true;
+        }
+        false
+    }
+
+    // Runs the rest of the current instruction
+    pub fn step(&mut self, cpu: &mut CPU) {
+        while !cpu.halted && !self.micro_step(cpu) {}
+    }
+
+    // Micro-ops of every microinstruction run for the current instruction
+    pub fn active_ops(&self) -> Vec<MicroOp> {
+        self.trace
+            .iter()
+            .filter_map(|&address| self.rom.words[address].as_ref())
+            .flat_map(|word| word.ops.iter().copied())
+            .collect()
+    }
+}
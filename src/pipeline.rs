@@ -0,0 +1,456 @@
+use crate::cpu::{CPU, OpCode, decode_word};
+use crate::predictor::{BranchPredictor, PredictorKind};
+
+pub const STAGE_NAMES: [&str; 5] = ["IF", "ID", "EX", "MEM", "WB"];
+
+// Number of cycles kept for the pipeline diagram
+const HISTORY_LENGTH: usize = 256;
+
+// NOTE: Pipeline latch contents
+// An instruction in flight along with the values it has picked up so far
+#[derive(Clone, Copy)]
+pub struct Slot {
+    pub pc: u16,
+    pub ir: u16,
+    pub value_a: u16, // Value of the register field read in ID, stack slot for CALL
+    pub value_b: u16, // Value of the register named by the operand, read in ID
+    pub result: u16,  // ALU result, loaded value or value to store
+    pub predicted: Option<u16>, // Target IF jumped to, if predicted taken
+}
+
+impl Slot {
+    fn new(pc: u16, ir: u16) -> Self {
+        Self {
+            pc,
+            ir,
+            value_a: 0,
+            value_b: 0,
+            result: 0,
+            predicted: None,
+        }
+    }
+
+    // Wrong-path fetches may be garbage, so illegal opcodes only stop the CPU once
+    // they retire
+    fn opcode(&self) -> Option<OpCode> {
+        let (opcode, _, _) = decode_word(self.ir);
+        OpCode::u8_to_opcode(opcode)
+    }
+
+    // Register written in WB, if any
+    fn destination(&self) -> Option<u8> {
+        let (_, register, _) = decode_word(self.ir);
+
+        use OpCode::*;
+        match self.opcode() {
+            Some(LVAL | LOAD | ADD | SUB | MOV) => Some(register & 0b11),
+            _ => None,
+        }
+    }
+
+    // Registers read in ID as (value_a, value_b)
+    fn sources(&self) -> (Option<u8>, Option<u8>) {
+        let (_, register, operand) = decode_word(self.ir);
+
+        use OpCode::*;
+        match self.opcode() {
+            Some(ADD | SUB) => (Some(register & 0b11), Some(operand & 0b11)),
+            Some(MOV) => (None, Some(operand & 0b11)),
+            Some(STORE | JNZ) => (Some(register & 0b11), None),
+            _ => (None, None),
+        }
+    }
+
+    fn is_load(&self) -> bool {
+        matches!(self.opcode(), Some(OpCode::LOAD))
+    }
+
+    fn accesses_memory(&self) -> bool {
+        matches!(
+            self.opcode(),
+            Some(OpCode::LOAD | OpCode::STORE | OpCode::CALL)
+        )
+    }
+
+    fn is_halt(&self) -> bool {
+        matches!(self.opcode(), Some(OpCode::HALT))
+    }
+
+    // HALT or an illegal instruction, nothing after it is fetched
+    fn stops(&self) -> bool {
+        self.is_halt() || self.opcode().is_none()
+    }
+
+    // Store done in MEM as (address, value), CALL pushes its return address
+    pub fn memory_write(&self) -> Option<(u8, u16)> {
+        let (_, _, operand) = decode_word(self.ir);
+        match self.opcode() {
+            Some(OpCode::STORE) => Some((operand, self.result)),
+            Some(OpCode::CALL) => Some((self.value_a as u8, self.result)),
+            _ => None,
+        }
+    }
+}
+
+// What happened in every stage during one cycle, used for the pipeline diagram
+#[derive(Clone, Copy)]
+pub struct CycleRecord {
+    pub cycle: u64,
+    pub stages: [Option<Slot>; 5], // IF, ID, EX, MEM, WB
+    pub stalled: bool,
+    pub flushed: bool,
+    pub waiting: bool,    // Frozen on a cache miss
+    pub structural: bool, // IF lost the memory port to MEM
+}
+
+// NOTE: Pipeline Definition
+// Five stage IF/ID/EX/MEM/WB model running on top of the CPU's registers and memory.
+// Each call to cycle() is one clock, cpu.pc is used as the fetch address.
+#[derive(Default)]
+pub struct Pipeline {
+    pub forwarding: bool,
+    pub predictor: BranchPredictor,
+    pub if_id: Option<Slot>,
+    pub id_ex: Option<Slot>,
+    pub ex_mem: Option<Slot>,
+    pub mem_wb: Option<Slot>,
+    pub stalls: u64,            // Bubbles inserted by data hazards
+    pub flushed_cycles: u64,    // Cycles lost to squashed instructions
+    pub memory_stalls: u64,     // Cycles frozen waiting on cache misses
+    pub structural_stalls: u64, // Fetches delayed by a LOAD/STORE using the single memory port
+    pub history: Vec<CycleRecord>,
+}
+
+impl Pipeline {
+    pub fn new(forwarding: bool, predictor: PredictorKind) -> Self {
+        Self {
+            forwarding,
+            predictor: BranchPredictor::new(predictor),
+            ..Default::default()
+        }
+    }
+
+    // Empty every stage but keep configuration
+    pub fn reset(&mut self) {
+        *self = Self::new(self.forwarding, self.predictor.kind);
+    }
+
+    // No instruction in flight
+    pub fn is_empty(&self) -> bool {
+        self.if_id.is_none()
+            && self.id_ex.is_none()
+            && self.ex_mem.is_none()
+            && self.mem_wb.is_none()
+    }
+
+    // Address of the oldest instruction in flight, the next one to retire
+    pub fn next_pc(&self) -> Option<u16> {
+        [&self.mem_wb, &self.ex_mem, &self.id_ex, &self.if_id]
+            .into_iter()
+            .find_map(|slot| slot.as_ref().map(|slot| slot.pc))
+    }
+
+    // SP as the last retired instruction left it. CALL and RET move SP in EX, so
+    // the ones past EX but not yet retired are undone
+    pub fn retired_sp(&self, cpu: &CPU) -> u16 {
+        [&self.ex_mem, &self.mem_wb]
+            .into_iter()
+            .flatten()
+            .fold(cpu.sp, |sp, slot| match slot.opcode() {
+                Some(OpCode::CALL) => sp.wrapping_add(1),
+                Some(OpCode::RET) => sp.wrapping_sub(1),
+                _ => sp,
+            })
+    }
+
+    // Registers that instructions in flight will still write in WB
+    pub fn pending_registers(&self) -> Vec<u8> {
+        [&self.if_id, &self.id_ex, &self.ex_mem, &self.mem_wb]
+            .into_iter()
+            .flatten()
+            .filter_map(Slot::destination)
+            .collect()
+    }
+
+    // NOTE: Clock
+    // Stages are evaluated from WB back to IF so each one sees last cycle's latches.
+    // Returns the instruction retired this cycle, if any.
+    pub fn cycle(&mut self, cpu: &mut CPU) -> Option<Slot> {
+        if cpu.halted {
+            return None;
+        }
+
+        let mut record = CycleRecord {
+            cycle: cpu.cycles,
+            stages: [None, self.if_id, self.id_ex, self.ex_mem, self.mem_wb],
+            stalled: false,
+            flushed: false,
+            waiting: false,
+            structural: false,
+        };
+
+        cpu.memory_writes.clear();
+
+        // A miss in IF or MEM freezes the whole pipeline for the miss penalty
+        if cpu.memory_stall > 0 {
+            cpu.memory_stall -= 1;
+            self.memory_stalls += 1;
+            record.waiting = true;
+            self.finish_cycle(cpu, record);
+            return None;
+        }
+
+        if cpu.timer.irq() && !cpu.in_interrupt {
+            self.interrupt(cpu);
+            record.stages = [None, self.if_id, self.id_ex, self.ex_mem, self.mem_wb];
+        }
+
+        // WB
+        let retired = self.mem_wb.take();
+        if let Some(slot) = retired {
+            self.write_back(cpu, &slot);
+            cpu.instructions += 1;
+
+            if cpu.halted {
+                // Everything behind HALT is squashed
+                self.if_id = None;
+                self.id_ex = None;
+                self.ex_mem = None;
+                cpu.pc = slot.pc.wrapping_add(1);
+                self.finish_cycle(cpu, record);
+                return retired;
+            }
+        }
+
+        // MEM
+        let port_busy =
+            !cpu.has_split_ports() && self.ex_mem.is_some_and(|slot| slot.accesses_memory());
+        let mem_wb = self.ex_mem.take().map(|slot| self.memory_access(cpu, slot));
+
+        // EX
+        let mut redirect = None;
+        let ex_mem = self.id_ex.take().map(|slot| {
+            let (slot, target) = self.execute(cpu, slot);
+            redirect = target;
+            slot
+        });
+
+        // ID
+        let mut id_ex = None;
+        let mut stalled = false;
+        if let Some(slot) = self.if_id
+            && redirect.is_none()
+        {
+            if self.hazard(&slot, ex_mem.as_ref(), mem_wb.as_ref()) {
+                stalled = true;
+            } else {
+                id_ex = Some(self.read_operands(cpu, slot, ex_mem.as_ref(), mem_wb.as_ref()));
+            }
+        }
+
+        // IF
+        // Nothing is fetched behind a HALT, the pipeline drains instead
+        let draining = [id_ex, ex_mem, mem_wb].iter().flatten().any(Slot::stops);
+
+        if let Some(target) = redirect {
+            // Mispredicted jump resolved in EX, squash the instructions in ID and IF
+            self.if_id = None;
+            self.flushed_cycles += 2;
+            cpu.pc = target;
+            record.flushed = true;
+        } else if stalled {
+            // Hold the instruction in ID and don't fetch
+            self.stalls += 1;
+            record.stalled = true;
+        } else if draining {
+            self.if_id = None;
+        } else if port_busy {
+            // Von Neumann: instructions and data share one memory port
+            self.if_id = None;
+            self.structural_stalls += 1;
+            record.structural = true;
+        } else {
+            let slot = self.fetch(cpu);
+            record.stages[0] = Some(slot);
+            self.if_id = Some(slot);
+        }
+
+        self.id_ex = id_ex;
+        self.ex_mem = ex_mem;
+        self.mem_wb = mem_wb;
+
+        self.finish_cycle(cpu, record);
+        retired
+    }
+
+    fn finish_cycle(&mut self, cpu: &mut CPU, record: CycleRecord) {
+        cpu.cycles += 1;
+        cpu.timer.tick(1);
+
+        self.history.push(record);
+        if self.history.len() > HISTORY_LENGTH {
+            self.history.remove(0);
+        }
+    }
+
+    // Squash the instructions that haven't executed yet and restart at the oldest of them
+    fn interrupt(&mut self, cpu: &mut CPU) {
+        let resume = self.id_ex.or(self.if_id).map_or(cpu.pc, |slot| slot.pc);
+        self.id_ex = None;
+        self.if_id = None;
+
+        cpu.epc = resume;
+        cpu.pc = cpu.timer.vector;
+        cpu.in_interrupt = true;
+    }
+
+    // NOTE: Stages
+    fn fetch(&self, cpu: &mut CPU) -> Slot {
+        let ir = cpu.fetch_word(cpu.pc);
+        let mut slot = Slot::new(cpu.pc, ir);
+        cpu.ir = ir;
+
+        // Follow the predictor, EX checks the guess
+        slot.predicted = self.predictor.predict(slot.pc, ir);
+        cpu.pc = slot.predicted.unwrap_or(cpu.pc.wrapping_add(1));
+        slot
+    }
+
+    // Data hazard check for the instruction in ID against the ones ahead of it.
+    // Register file is written in the first half of WB, so WB never conflicts.
+    fn hazard(&self, slot: &Slot, ex: Option<&Slot>, mem: Option<&Slot>) -> bool {
+        let (source_a, source_b) = slot.sources();
+        let reads = |register: Option<u8>| {
+            register.is_some() && (register == source_a || register == source_b)
+        };
+
+        let ex_writes = ex.and_then(Slot::destination);
+        let mem_writes = mem.and_then(Slot::destination);
+
+        if self.forwarding {
+            // Only a load followed by a use has to wait, its value comes out of MEM
+            ex.is_some_and(Slot::is_load) && reads(ex_writes)
+        } else {
+            reads(ex_writes) || reads(mem_writes)
+        }
+    }
+
+    fn read_operands(
+        &self,
+        cpu: &CPU,
+        mut slot: Slot,
+        ex: Option<&Slot>,
+        mem: Option<&Slot>,
+    ) -> Slot {
+        let (source_a, source_b) = slot.sources();
+
+        let read = |register: Option<u8>| -> u16 {
+            let Some(register) = register else {
+                return 0;
+            };
+
+            // Newest value wins when forwarding from EX/MEM and MEM/WB
+            if self.forwarding {
+                for producer in [ex, mem].into_iter().flatten() {
+                    if producer.destination() == Some(register) {
+                        return producer.result;
+                    }
+                }
+            }
+            cpu.get_register(register)
+        };
+
+        slot.value_a = read(source_a);
+        slot.value_b = read(source_b);
+        slot
+    }
+
+    // Returns the slot and the correct next PC if IF went the wrong way
+    fn execute(&mut self, cpu: &mut CPU, mut slot: Slot) -> (Slot, Option<u16>) {
+        let (opcode, _, operand) = decode_word(slot.ir);
+        let Some(opcode) = OpCode::u8_to_opcode(opcode) else {
+            // Does nothing until WB, nothing behind it was fetched
+            return (slot, None);
+        };
+
+        use OpCode::*;
+        let target = match opcode {
+            LVAL => {
+                slot.result = operand as u16;
+                None
+            }
+            ADD => {
+                slot.result = slot.value_a.wrapping_add(slot.value_b);
+                None
+            }
+            SUB => {
+                slot.result = slot.value_a.wrapping_sub(slot.value_b);
+                None
+            }
+            MOV => {
+                slot.result = slot.value_b;
+                None
+            }
+            STORE => {
+                slot.result = slot.value_a;
+                None
+            }
+            JMP => Some(operand as u16),
+            JNZ => (slot.value_a != 0).then_some(operand as u16),
+            RETI => {
+                cpu.in_interrupt = false;
+                Some(cpu.epc)
+            }
+            // SP is updated in EX so back to back calls see each other's pushes
+            CALL => {
+                cpu.sp = cpu.sp.wrapping_sub(1);
+                slot.value_a = cpu.sp;
+                slot.result = slot.pc.wrapping_add(1);
+                Some(operand as u16)
+            }
+            // The return address is popped in EX so RET can redirect like RETI, the
+            // CALL's push is always a stage ahead and done by then
+            RET => {
+                let address = cpu.read_memory(cpu.sp as u8);
+                cpu.sp = cpu.sp.wrapping_add(1);
+                Some(address)
+            }
+            HALT | LOAD => None,
+        };
+
+        let next = slot.pc.wrapping_add(1);
+        let actual = target.unwrap_or(next);
+        let correct = actual == slot.predicted.unwrap_or(next);
+
+        if matches!(opcode, JMP | JNZ) {
+            self.predictor
+                .update(slot.pc, target.is_some(), actual, correct);
+        }
+
+        (slot, (!correct).then_some(actual))
+    }
+
+    fn memory_access(&self, cpu: &mut CPU, mut slot: Slot) -> Slot {
+        let (_, _, operand) = decode_word(slot.ir);
+
+        match slot.opcode() {
+            Some(OpCode::LOAD) => slot.result = cpu.read_memory(operand),
+            Some(OpCode::STORE) => cpu.write_memory(operand, slot.result),
+            Some(OpCode::CALL) => cpu.write_memory(slot.value_a as u8, slot.result),
+            _ => {}
+        }
+        slot
+    }
+
+    fn write_back(&self, cpu: &mut CPU, slot: &Slot) {
+        if let Some(register) = slot.destination() {
+            *cpu.get_register_mut(register) = slot.result;
+        }
+
+        if slot.is_halt() {
+            cpu.halted = true;
+        } else if slot.opcode().is_none() {
+            cpu.illegal_instruction(slot.pc, slot.ir);
+        }
+    }
+}
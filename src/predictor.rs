@@ -0,0 +1,147 @@
+use crate::cpu::{OpCode, decode_word};
+
+use std::collections::{BTreeMap, HashMap};
+
+// NOTE: Predictor kinds
+#[derive(Clone, Copy, Default)]
+pub enum PredictorKind {
+    #[default]
+    AlwaysNotTaken,
+    OneBit,       // Remembers the last outcome per branch
+    TwoBit,       // Saturating counter, needs two misses in a row to flip
+    TargetBuffer, // BTB with 2-bit counters, predicts targets without decoding
+}
+
+impl PredictorKind {
+    pub fn from_name(name: &str) -> Option<PredictorKind> {
+        match name {
+            "not-taken" => Some(PredictorKind::AlwaysNotTaken),
+            "1bit" => Some(PredictorKind::OneBit),
+            "2bit" => Some(PredictorKind::TwoBit),
+            "btb" => Some(PredictorKind::TargetBuffer),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PredictorKind::AlwaysNotTaken => "always not taken",
+            PredictorKind::OneBit => "1-bit",
+            PredictorKind::TwoBit => "2-bit saturating",
+            PredictorKind::TargetBuffer => "BTB",
+        }
+    }
+}
+
+// Outcome counts for a single branch address
+#[derive(Clone, Copy, Default)]
+pub struct BranchStats {
+    pub executed: u64,
+    pub taken: u64,
+    pub correct: u64,
+}
+
+impl BranchStats {
+    pub fn accuracy(&self) -> f64 {
+        if self.executed == 0 {
+            0.0
+        } else {
+            self.correct as f64 / self.executed as f64 * 100.0
+        }
+    }
+}
+
+// NOTE: Predictor Definition
+// Consulted in IF and trained in EX once the branch is resolved
+#[derive(Default)]
+pub struct BranchPredictor {
+    pub kind: PredictorKind,
+    counters: HashMap<u16, u8>,       // Per-address history (1 or 2 bits)
+    targets: HashMap<u16, (u16, u8)>, // BTB entries: (target, 2-bit counter)
+    pub stats: BTreeMap<u16, BranchStats>,
+}
+
+impl BranchPredictor {
+    pub fn new(kind: PredictorKind) -> Self {
+        Self {
+            kind,
+            ..Default::default()
+        }
+    }
+
+    // Returns the predicted target if the instruction is predicted taken.
+    // Direction predictors pre-decode the fetched word to find the target.
+    pub fn predict(&self, pc: u16, ir: u16) -> Option<u16> {
+        let (opcode, _, operand) = decode_word(ir);
+        let is_branch = matches!(
+            OpCode::u8_to_opcode(opcode),
+            Some(OpCode::JMP | OpCode::JNZ)
+        );
+
+        match self.kind {
+            PredictorKind::AlwaysNotTaken => None,
+            PredictorKind::OneBit => (is_branch && self.counter(pc) == 1).then_some(operand as u16),
+            PredictorKind::TwoBit => (is_branch && self.counter(pc) >= 2).then_some(operand as u16),
+            PredictorKind::TargetBuffer => match self.targets.get(&pc) {
+                Some(&(target, counter)) if counter >= 2 => Some(target),
+                _ => None,
+            },
+        }
+    }
+
+    // Train on the resolved outcome and record whether the prediction was right
+    pub fn update(&mut self, pc: u16, taken: bool, target: u16, correct: bool) {
+        let stats = self.stats.entry(pc).or_default();
+        stats.executed += 1;
+        stats.taken += taken as u64;
+        stats.correct += correct as u64;
+
+        match self.kind {
+            PredictorKind::AlwaysNotTaken => {}
+            PredictorKind::OneBit => {
+                self.counters.insert(pc, taken as u8);
+            }
+            PredictorKind::TwoBit => {
+                let counter = self.counters.entry(pc).or_insert(1); // Weakly not taken
+                *counter = saturate(*counter, taken);
+            }
+            PredictorKind::TargetBuffer => {
+                // Only taken branches are allocated an entry
+                if let Some((entry_target, counter)) = self.targets.get_mut(&pc) {
+                    *counter = saturate(*counter, taken);
+                    if taken {
+                        *entry_target = target;
+                    }
+                } else if taken {
+                    self.targets.insert(pc, (target, 2)); // Weakly taken
+                }
+            }
+        }
+    }
+
+    pub fn total(&self) -> BranchStats {
+        self.stats
+            .values()
+            .fold(BranchStats::default(), |total, stats| BranchStats {
+                executed: total.executed + stats.executed,
+                taken: total.taken + stats.taken,
+                correct: total.correct + stats.correct,
+            })
+    }
+
+    fn counter(&self, pc: u16) -> u8 {
+        self.counters.get(&pc).copied().unwrap_or(match self.kind {
+            PredictorKind::TwoBit => 1,
+            _ => 0,
+        })
+    }
+}
+
+// 2-bit saturating counter, 0-1 predict not taken and 2-3 predict taken
+fn saturate(counter: u8, taken: bool) -> u8 {
+    if taken {
+        (counter + 1).min(3)
+    } else {
+        counter.saturating_sub(1)
+    }
+}
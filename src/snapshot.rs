@@ -0,0 +1,264 @@
+use crate::cpu::CPU;
+use crate::timer::Timer;
+
+use std::collections::HashMap;
+use std::{fs, io};
+
+// First line of every snapshot file, bump the version when the format changes
+const HEADER: &str = "cpu-sim snapshot";
+const VERSION: u32 = 2;
+
+// NOTE: Machine snapshots
+// Plain key=value text so snapshots can be read and tweaked by hand. Dirty cache
+// lines are written into the saved memory image and caches start cold on load.
+pub fn save(cpu: &CPU, path: &str) -> io::Result<()> {
+    fs::write(path, to_text(cpu))
+}
+
+pub fn load(cpu: &mut CPU, path: &str) -> io::Result<()> {
+    let text = fs::read_to_string(path)?;
+    from_text(cpu, &text)
+        .map_err(|message| io::Error::new(io::ErrorKind::InvalidData, format!("{path}: {message}")))
+}
+
+pub fn to_text(cpu: &CPU) -> String {
+    let registers = cpu.get_all_registers();
+
+    let memory = cpu.flushed_memory();
+
+    let mut lines = vec![
+        format!("{HEADER} {VERSION}"),
+        format!("pc={}", cpu.pc),
+        format!("ir={}", cpu.ir),
+        format!("sp={}", cpu.sp),
+        format!(
+            "registers={} {} {} {}",
+            registers[0], registers[1], registers[2], registers[3]
+        ),
+        format!("mar={}", cpu.mar),
+        format!("mdr={}", cpu.mdr),
+        format!("alu={} {} {}", cpu.alu_a, cpu.alu_b, cpu.alu_out),
+        format!("halted={}", cpu.halted as u8),
+        format!("in_interrupt={}", cpu.in_interrupt as u8),
+        format!("epc={}", cpu.epc),
+        format!("cycles={}", cpu.cycles),
+        format!("instructions={}", cpu.instructions),
+        format!(
+            "timer={} {} {} {}",
+            cpu.timer.reload, cpu.timer.count, cpu.timer.control, cpu.timer.vector
+        ),
+        format!("memory={}", words(&memory)),
+    ];
+    if let Some(instruction_memory) = &cpu.instruction_memory {
+        lines.push(format!("instruction_memory={}", words(instruction_memory)));
+    }
+
+    lines.join("\n") + "\n"
+}
+
+// Restores everything but configuration (cycle costs, cache geometry, architecture),
+// reset() keeps those and empties the caches
+pub fn from_text(cpu: &mut CPU, text: &str) -> Result<(), String> {
+    let mut lines = text.lines();
+
+    let version = lines
+        .next()
+        .and_then(|line| line.strip_prefix(HEADER))
+        .ok_or_else(|| String::from("Not a snapshot file"))?;
+    if version.trim() != VERSION.to_string() {
+        return Err(format!(
+            "Unsupported snapshot version {}, expected {VERSION}",
+            version.trim()
+        ));
+    }
+
+    let mut fields = HashMap::new();
+    for line in lines.map(str::trim).filter(|line| !line.is_empty()) {
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("Expected key=value, got {line}"))?;
+        fields.insert(key, value);
+    }
+
+    let numbers = |key: &str, count: usize| -> Result<Vec<u64>, String> {
+        let value = fields.get(key).ok_or_else(|| format!("Missing {key}"))?;
+        let values = value
+            .split_whitespace()
+            .map(|number| number.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("Invalid number in {key}"))?;
+
+        if values.len() != count {
+            return Err(format!(
+                "Expected {count} values for {key}, got {}",
+                values.len()
+            ));
+        }
+        Ok(values)
+    };
+    let word = |key: &str| -> Result<u16, String> {
+        let value = numbers(key, 1)?[0];
+        u16::try_from(value).map_err(|_| format!("{key} doesn't fit in 16 bits"))
+    };
+    let memory = |key: &str| -> Result<[u16; 64], String> {
+        let mut memory = [0u16; 64];
+        for (cell, value) in memory.iter_mut().zip(numbers(key, 64)?) {
+            *cell = u16::try_from(value).map_err(|_| format!("{key} doesn't fit in 16 bits"))?;
+        }
+        Ok(memory)
+    };
+
+    if fields.contains_key("instruction_memory") != cpu.instruction_memory.is_some() {
+        return Err(String::from(
+            "Snapshot was taken on a different memory architecture (see --harvard)",
+        ));
+    }
+
+    // Parse everything before touching the CPU so a bad file leaves it unchanged
+    let registers = numbers("registers", 4)?;
+    let alu = numbers("alu", 3)?;
+    let timer = numbers("timer", 4)?;
+    let data_memory = memory("memory")?;
+    let instruction_memory = match cpu.instruction_memory {
+        Some(_) => Some(memory("instruction_memory")?),
+        None => None,
+    };
+    if registers
+        .iter()
+        .chain(&alu)
+        .chain(&timer)
+        .any(|&value| value > u16::MAX as u64)
+    {
+        return Err(String::from("Register value doesn't fit in 16 bits"));
+    }
+
+    let (pc, ir, sp, mar, mdr, epc) = (
+        word("pc")?,
+        word("ir")?,
+        word("sp")?,
+        word("mar")?,
+        word("mdr")?,
+        word("epc")?,
+    );
+    let halted = word("halted")? != 0;
+    let in_interrupt = word("in_interrupt")? != 0;
+    let cycles = numbers("cycles", 1)?[0];
+    let instructions = numbers("instructions", 1)?[0];
+
+    cpu.reset(&[]);
+    cpu.pc = pc;
+    cpu.ir = ir;
+    cpu.sp = sp;
+    cpu.mar = mar;
+    cpu.mdr = mdr;
+    cpu.alu_a = alu[0] as u16;
+    cpu.alu_b = alu[1] as u16;
+    cpu.alu_out = alu[2] as u16;
+    cpu.memory = data_memory;
+    cpu.instruction_memory = instruction_memory;
+    cpu.halted = halted;
+    cpu.cycles = cycles;
+    cpu.instructions = instructions;
+    cpu.epc = epc;
+    cpu.in_interrupt = in_interrupt;
+    cpu.timer = Timer {
+        reload: timer[0] as u16,
+        count: timer[1] as u16,
+        control: timer[2] as u16,
+        vector: timer[3] as u16,
+    };
+    for (register, &value) in registers.iter().enumerate() {
+        *cpu.get_register_mut(register as u8) = value as u16;
+    }
+
+    Ok(())
+}
+
+fn words(memory: &[u16]) -> String {
+    memory
+        .iter()
+        .map(|word| word.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LVAL R0, 5 / LVAL R1, 3 / ADD R0, R1 / STORE R0, 40 / HALT
+    const PROGRAM: [u16; 5] = [0x1005, 0x1103, 0x4001, 0x3028, 0x0000];
+
+    fn stepped(cpu: &mut CPU, steps: usize) {
+        for _ in 0..steps {
+            cpu.step();
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let mut cpu = CPU::new(&PROGRAM);
+        stepped(&mut cpu, 4);
+        cpu.timer.reload = 100;
+        cpu.epc = 7;
+        let path = std::env::temp_dir().join(format!("cpu-sim-{}.snap", std::process::id()));
+        let path = path.to_str().unwrap();
+        save(&cpu, path).unwrap();
+
+        let mut loaded = CPU::new(&[]);
+        let result = load(&mut loaded, path);
+        fs::remove_file(path).unwrap();
+        result.unwrap();
+
+        assert_eq!(to_text(&loaded), to_text(&cpu));
+        assert_eq!(loaded.get_all_registers(), [8, 3, 0, 0]);
+        assert_eq!(loaded.memory[40], 8);
+        assert_eq!((loaded.pc, loaded.instructions), (4, 4));
+    }
+
+    #[test]
+    fn harvard_round_trip_keeps_instruction_memory() {
+        let mut cpu = CPU::new(&PROGRAM);
+        cpu.split_memories();
+        stepped(&mut cpu, 4);
+
+        let mut loaded = CPU::new(&[]);
+        loaded.split_memories();
+        from_text(&mut loaded, &to_text(&cpu)).unwrap();
+        assert_eq!(to_text(&loaded), to_text(&cpu));
+        assert_eq!(loaded.instruction_memory, cpu.instruction_memory);
+    }
+
+    #[test]
+    fn bad_snapshots_leave_the_cpu_unchanged() {
+        let mut cpu = CPU::new(&PROGRAM);
+        stepped(&mut cpu, 2);
+        let before = to_text(&cpu);
+        let text = to_text(&CPU::new(&[]));
+
+        let wrong_version = text.replacen("snapshot 2", "snapshot 1", 1);
+        assert_eq!(
+            from_text(&mut cpu, &wrong_version),
+            Err(String::from("Unsupported snapshot version 1, expected 2"))
+        );
+        let missing = text.replacen("\nsp=64\n", "\n", 1);
+        assert_eq!(
+            from_text(&mut cpu, &missing),
+            Err(String::from("Missing sp"))
+        );
+        let too_big = text.replacen("\npc=0\n", "\npc=70000\n", 1);
+        assert_eq!(
+            from_text(&mut cpu, &too_big),
+            Err(String::from("pc doesn't fit in 16 bits"))
+        );
+        assert_eq!(
+            from_text(&mut cpu, "registers=1 2 3 4"),
+            Err(String::from("Not a snapshot file"))
+        );
+
+        let mut harvard = CPU::new(&[]);
+        harvard.split_memories();
+        assert!(from_text(&mut harvard, &text).is_err());
+        assert_eq!(to_text(&cpu), before);
+    }
+}
@@ -0,0 +1,131 @@
+// NOTE: Assembly syntax
+// Shared by the assembler and the simulator's edit prompt, so literals, registers and
+// operands read the same wherever an instruction is typed
+
+pub enum Register {
+    R0 = 0b0000,
+    R1 = 0b0001,
+    R2 = 0b0010,
+    R3 = 0b0011,
+}
+
+pub fn parse_register(arg: &str) -> Result<u8, String> {
+    match arg {
+        "R0" => Ok(Register::R0 as u8),
+        "R1" => Ok(Register::R1 as u8),
+        "R2" => Ok(Register::R2 as u8),
+        "R3" => Ok(Register::R3 as u8),
+        _ => Err(format!("Unknown register {arg}")),
+    }
+}
+
+// A value in a field of the given width. Negative values are stored as two's
+// complement, anything that doesn't fit is an error rather than being cut off.
+pub fn fit(arg: &str, value: i64, bits: u32) -> Result<u16, String> {
+    let max = (1i64 << bits) - 1;
+    let min = -(1i64 << (bits - 1));
+    if value < min || value > max {
+        return Err(format!(
+            "{} doesn't fit in {bits} bits ({min} to {max})",
+            shown(arg, value)
+        ));
+    }
+    Ok((value & max) as u16)
+}
+
+// An operand for an error message, with its value unless it's a plain number
+pub fn shown(arg: &str, value: i64) -> String {
+    if arg == value.to_string() {
+        String::from(arg)
+    } else {
+        format!("{arg} = {value}")
+    }
+}
+
+// NOTE: Literals
+// Decimal, 0x hex, 0b binary, 0o octal and 'c' characters
+pub fn parse_literal(arg: &str) -> Result<i64, String> {
+    if let Some(quoted) = arg.strip_prefix('\'') {
+        return parse_char(quoted).ok_or_else(|| format!("Invalid character literal {arg}"));
+    }
+
+    let (negative, digits) = match arg.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, arg),
+    };
+    let lower = digits.to_lowercase();
+    let (radix, digits) = if let Some(hex) = lower.strip_prefix("0x") {
+        (16, hex)
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        (2, binary)
+    } else if let Some(octal) = lower.strip_prefix("0o") {
+        (8, octal)
+    } else {
+        (10, lower.as_str())
+    };
+
+    // Digits only, from_str_radix would also take a second sign
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(format!("Invalid number {arg}"));
+    }
+    let value =
+        i64::from_str_radix(digits, radix).map_err(|_| format!("Number {arg} is too large"))?;
+    Ok(if negative { -value } else { value })
+}
+
+// The rest of a character literal after the opening quote, e.g. A' or \n'
+fn parse_char(quoted: &str) -> Option<i64> {
+    if quoted.starts_with('\'') {
+        return None;
+    }
+    let mut chars = quoted.chars();
+    let c = literal_char(&mut chars)?;
+    (chars.as_str() == "'").then_some(c as i64)
+}
+
+// One character of a literal, backslash escapes included
+pub fn literal_char(chars: &mut std::str::Chars) -> Option<char> {
+    match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => Some('\n'),
+            't' => Some('\t'),
+            'r' => Some('\r'),
+            '0' => Some('\0'),
+            c @ ('\\' | '\'' | '"') => Some(c),
+            _ => None,
+        },
+        c => Some(c),
+    }
+}
+
+// Comma separated operands, commas inside literals don't count
+pub fn split_operands(args: &str) -> Vec<&str> {
+    let mut operands = Vec::new();
+    let mut start = 0;
+    for (i, _) in unquoted(args).filter(|&(_, c)| c == ',') {
+        operands.push(args[start..i].trim());
+        start = i + 1;
+    }
+    let last = args[start..].trim();
+    if !last.is_empty() || !operands.is_empty() {
+        operands.push(last);
+    }
+    operands
+}
+
+// Characters outside character and string literals, with their positions
+pub fn unquoted(text: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let mut quote = None;
+    let mut escaped = false;
+    text.char_indices().filter(move |&(_, c)| {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None => return true,
+        }
+        false
+    })
+}
@@ -0,0 +1,88 @@
+// NOTE: Memory-mapped timer registers
+// Addresses from IO_BASE upwards are routed to devices instead of memory
+pub const IO_BASE: u8 = 0xF0;
+pub const TIMER_RELOAD: u8 = 0xF0;
+pub const TIMER_COUNT: u8 = 0xF1;
+pub const TIMER_CONTROL: u8 = 0xF2;
+pub const TIMER_VECTOR: u8 = 0xF3;
+
+// Control register bits
+pub const CONTROL_ENABLE: u16 = 0b0001;
+pub const CONTROL_IRQ: u16 = 0b0010; // Raise an interrupt on expiry
+pub const CONTROL_AUTO_RELOAD: u16 = 0b0100; // Restart from reload value on expiry
+pub const CONTROL_EXPIRED: u16 = 0b1000; // Set by the timer, cleared on control write
+
+// NOTE: Timer Definition
+// Counts down once per CPU cycle while enabled
+#[derive(Clone, Default, PartialEq)]
+pub struct Timer {
+    pub reload: u16,
+    pub count: u16,
+    pub control: u16,
+    pub vector: u16,
+}
+
+impl Timer {
+    pub fn read(&self, address: u8) -> u16 {
+        match address {
+            TIMER_RELOAD => self.reload,
+            TIMER_COUNT => self.count,
+            TIMER_CONTROL => self.control,
+            TIMER_VECTOR => self.vector,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, address: u8, value: u16) {
+        match address {
+            TIMER_RELOAD => self.reload = value,
+            TIMER_COUNT => self.count = value,
+            // Writing control acknowledges expiry, so the expired bit can't be set by software
+            TIMER_CONTROL => self.control = value & !CONTROL_EXPIRED,
+            TIMER_VECTOR => self.vector = value,
+            _ => {}
+        }
+    }
+
+    // Advance the timer by the number of cycles the CPU just spent
+    pub fn tick(&mut self, cycles: u64) {
+        for _ in 0..cycles {
+            if self.control & CONTROL_ENABLE == 0 {
+                return;
+            }
+
+            self.count = self.count.saturating_sub(1);
+            if self.count == 0 {
+                self.control |= CONTROL_EXPIRED;
+
+                if self.control & CONTROL_AUTO_RELOAD != 0 && self.reload != 0 {
+                    self.count = self.reload;
+                } else {
+                    self.control &= !CONTROL_ENABLE; // One-shot
+                }
+            }
+        }
+    }
+
+    // Interrupt line is level triggered and stays high until expiry is acknowledged
+    pub fn irq(&self) -> bool {
+        self.control & CONTROL_IRQ != 0 && self.control & CONTROL_EXPIRED != 0
+    }
+
+    // Helper function for TUI specifically
+    pub fn control_flags(&self) -> String {
+        let flags = [
+            (CONTROL_ENABLE, "EN"),
+            (CONTROL_IRQ, "IRQ"),
+            (CONTROL_AUTO_RELOAD, "AR"),
+            (CONTROL_EXPIRED, "EXP"),
+        ];
+
+        flags
+            .iter()
+            .filter(|(bit, _)| self.control & bit != 0)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
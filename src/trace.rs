@@ -0,0 +1,387 @@
+use crate::cpu::{CPU, disassemble};
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+
+// Oldest records are dropped past this so long runs don't grow without bound
+pub const TRACE_LIMIT: usize = 100_000;
+
+// NOTE: Trace record
+// One completed instruction
+#[derive(Clone)]
+pub struct TraceRecord {
+    pub cycle: u64, // Cycle count once the instruction completed
+    pub pc: u16,
+    pub ir: u16,
+    pub registers: [u16; 4],                   // Values afterwards
+    pub register_changes: Vec<(u8, u16, u16)>, // (register, old, new)
+    pub memory_writes: Vec<(u8, u16)>,         // (address, value)
+}
+
+pub enum TraceFormat {
+    Csv,
+    JsonLines,
+}
+
+impl TraceFormat {
+    // .csv is CSV, anything else is JSON Lines
+    pub fn from_path(path: &str) -> TraceFormat {
+        if path.to_lowercase().ends_with(".csv") {
+            TraceFormat::Csv
+        } else {
+            TraceFormat::JsonLines
+        }
+    }
+}
+
+// NOTE: Trace Definition
+// Callers record each instruction as it completes, register changes are found by
+// comparing against the values at the previous record
+#[derive(Default)]
+pub struct Trace {
+    pub records: VecDeque<TraceRecord>,
+    pub dropped: u64, // Records lost off the front, exports say so
+    registers: [u16; 4],
+}
+
+impl Trace {
+    pub fn new(cpu: &CPU) -> Self {
+        Self {
+            registers: cpu.get_all_registers(),
+            ..Default::default()
+        }
+    }
+
+    // Start over from the CPU's current state
+    pub fn reset(&mut self, cpu: &CPU) {
+        *self = Self::new(cpu);
+    }
+
+    // Take the CPU's registers as the baseline, so edits aren't blamed on the next instruction
+    pub fn sync_registers(&mut self, cpu: &CPU) {
+        self.registers = cpu.get_all_registers();
+    }
+
+    pub fn record(&mut self, cpu: &CPU, pc: u16, ir: u16, memory_writes: Vec<(u8, u16)>) {
+        let registers = cpu.get_all_registers();
+        let register_changes = (0..4)
+            .filter(|&i| registers[i] != self.registers[i])
+            .map(|i| (i as u8, self.registers[i], registers[i]))
+            .collect();
+        self.registers = registers;
+
+        self.records.push_back(TraceRecord {
+            cycle: cpu.cycles,
+            pc,
+            ir,
+            registers,
+            register_changes,
+            memory_writes,
+        });
+        if self.records.len() > TRACE_LIMIT {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+    }
+
+    // Warning once the oldest records have been dropped
+    pub fn truncated(&self) -> Option<String> {
+        (self.dropped > 0).then(|| {
+            format!(
+                "Trace holds the last {TRACE_LIMIT} instructions, the first {} were dropped",
+                self.dropped
+            )
+        })
+    }
+
+    // Record the instruction the CPU just finished (not pipelined)
+    pub fn record_step(&mut self, cpu: &CPU) {
+        self.record(cpu, cpu.fetch_address, cpu.ir, cpu.memory_writes.clone());
+    }
+
+    pub fn export(&self, path: &str) -> io::Result<()> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        match TraceFormat::from_path(path) {
+            TraceFormat::Csv => self.write_csv(&mut file)?,
+            TraceFormat::JsonLines => self.write_json_lines(&mut file)?,
+        }
+        file.flush()
+    }
+
+    // Disassembly is quoted since it contains commas, changes and writes are space
+    // separated lists, e.g. "R0:4->5" and "40=15". A truncated trace starts with a
+    // "# dropped=N" comment.
+    pub fn write_csv(&self, out: &mut impl Write) -> io::Result<()> {
+        if self.dropped > 0 {
+            writeln!(out, "# dropped={}", self.dropped)?;
+        }
+        writeln!(
+            out,
+            "cycle,pc,ir,disassembly,r0,r1,r2,r3,register_changes,memory_writes"
+        )?;
+        for record in &self.records {
+            let changes: Vec<String> = record
+                .register_changes
+                .iter()
+                .map(|(register, old, new)| format!("R{register}:{old}->{new}"))
+                .collect();
+            let writes: Vec<String> = record
+                .memory_writes
+                .iter()
+                .map(|(address, value)| format!("{address}={value}"))
+                .collect();
+
+            writeln!(
+                out,
+                "{},{},{},\"{}\",{},{},{},{},{},{}",
+                record.cycle,
+                record.pc,
+                record.ir,
+                disassemble(record.ir),
+                record.registers[0],
+                record.registers[1],
+                record.registers[2],
+                record.registers[3],
+                changes.join(" "),
+                writes.join(" ")
+            )?;
+        }
+        Ok(())
+    }
+
+    // A truncated trace starts with a {"dropped":N} line
+    pub fn write_json_lines(&self, out: &mut impl Write) -> io::Result<()> {
+        if self.dropped > 0 {
+            writeln!(out, "{{\"dropped\":{}}}", self.dropped)?;
+        }
+        for record in &self.records {
+            let changes: Vec<String> = record
+                .register_changes
+                .iter()
+                .map(|(register, old, new)| {
+                    format!("{{\"register\":\"R{register}\",\"old\":{old},\"new\":{new}}}")
+                })
+                .collect();
+            let writes: Vec<String> = record
+                .memory_writes
+                .iter()
+                .map(|(address, value)| format!("{{\"address\":{address},\"value\":{value}}}"))
+                .collect();
+
+            writeln!(
+                out,
+                "{{\"cycle\":{},\"pc\":{},\"ir\":{},\"disassembly\":\"{}\",\"registers\":[{},{},{},{}],\"register_changes\":[{}],\"memory_writes\":[{}]}}",
+                record.cycle,
+                record.pc,
+                record.ir,
+                disassemble(record.ir),
+                record.registers[0],
+                record.registers[1],
+                record.registers[2],
+                record.registers[3],
+                changes.join(","),
+                writes.join(",")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// NOTE: Reading traces back
+// Only understands files written by export(), register changes are left empty.
+// Returns the records with how many were dropped before the first of them.
+pub fn read(path: &str) -> io::Result<(u64, Vec<TraceRecord>)> {
+    let text = fs::read_to_string(path)?;
+    let (parse, dropped_prefix, dropped_suffix): (fn(&str) -> Option<TraceRecord>, _, _) =
+        match TraceFormat::from_path(path) {
+            TraceFormat::Csv => (parse_csv_line, "# dropped=", ""),
+            TraceFormat::JsonLines => (parse_json_line, "{\"dropped\":", "}"),
+        };
+
+    let mut dropped = 0;
+    let mut records = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with("cycle,") {
+            continue;
+        }
+        let malformed = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{path}:{}: Malformed trace record", number + 1),
+            )
+        };
+        if let Some(count) = line
+            .strip_prefix(dropped_prefix)
+            .and_then(|rest| rest.strip_suffix(dropped_suffix))
+        {
+            dropped = count.trim().parse().map_err(|_| malformed())?;
+            continue;
+        }
+        records.push(parse(line).ok_or_else(malformed)?);
+    }
+    Ok((dropped, records))
+}
+
+fn parse_csv_line(line: &str) -> Option<TraceRecord> {
+    // Only the disassembly is quoted
+    let (head, rest) = line.split_once(",\"")?;
+    let (_, tail) = rest.split_once("\",")?;
+    let head: Vec<&str> = head.split(',').collect();
+    let tail: Vec<&str> = tail.split(',').collect();
+    let [cycle, pc, ir] = head[..] else {
+        return None;
+    };
+    let [r0, r1, r2, r3, _, writes] = tail[..] else {
+        return None;
+    };
+
+    let memory_writes = writes
+        .split_whitespace()
+        .map(|write| {
+            let (address, value) = write.split_once('=')?;
+            Some((address.parse().ok()?, value.parse().ok()?))
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(TraceRecord {
+        cycle: cycle.parse().ok()?,
+        pc: pc.parse().ok()?,
+        ir: ir.parse().ok()?,
+        registers: [
+            r0.parse().ok()?,
+            r1.parse().ok()?,
+            r2.parse().ok()?,
+            r3.parse().ok()?,
+        ],
+        register_changes: Vec::new(),
+        memory_writes,
+    })
+}
+
+fn parse_json_line(line: &str) -> Option<TraceRecord> {
+    // Numbers following "key": up to the next delimiter
+    let numbers_after = |text: &str, key: &str| -> Vec<u64> {
+        text.split(&format!("\"{key}\":"))
+            .skip(1)
+            .filter_map(|rest| {
+                let end = rest.find([',', '}', ']']).unwrap_or(rest.len());
+                rest[..end].trim().parse().ok()
+            })
+            .collect()
+    };
+    let field = |key: &str| numbers_after(line, key).first().copied();
+
+    let registers_start = line.find("\"registers\":[")? + "\"registers\":[".len();
+    let registers_end = registers_start + line[registers_start..].find(']')?;
+    let registers = line[registers_start..registers_end]
+        .split(',')
+        .map(|value| value.trim().parse::<u16>().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    let writes = &line[line.find("\"memory_writes\":")?..];
+    let addresses = numbers_after(writes, "address");
+    let values = numbers_after(writes, "value");
+
+    Some(TraceRecord {
+        cycle: field("cycle")?,
+        pc: u16::try_from(field("pc")?).ok()?,
+        ir: u16::try_from(field("ir")?).ok()?,
+        registers: registers.try_into().ok()?,
+        register_changes: Vec::new(),
+        memory_writes: addresses
+            .into_iter()
+            .zip(values)
+            .map(|(address, value)| Some((u8::try_from(address).ok()?, u16::try_from(value).ok()?)))
+            .collect::<Option<Vec<_>>>()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LVAL R0, 5 / LVAL R1, 3 / ADD R0, R1 / STORE R0, 40 / HALT
+    const PROGRAM: [u16; 5] = [0x1005, 0x1103, 0x4001, 0x3028, 0x0000];
+
+    fn traced(dropped: u64) -> Trace {
+        let mut cpu = CPU::new(&PROGRAM);
+        let mut trace = Trace::new(&cpu);
+        while !cpu.halted {
+            cpu.step();
+            trace.record_step(&cpu);
+        }
+        trace.dropped = dropped;
+        trace
+    }
+
+    // Everything read() gives back, register changes aren't
+    type Fields = (u64, u16, u16, [u16; 4], Vec<(u8, u16)>);
+
+    fn fields<'a>(records: impl IntoIterator<Item = &'a TraceRecord>) -> Vec<Fields> {
+        records
+            .into_iter()
+            .map(|record| {
+                (
+                    record.cycle,
+                    record.pc,
+                    record.ir,
+                    record.registers,
+                    record.memory_writes.clone(),
+                )
+            })
+            .collect()
+    }
+
+    fn round_trip(extension: &str, dropped: u64) {
+        let trace = traced(dropped);
+        let path = std::env::temp_dir().join(format!(
+            "cpu-sim-trace-{}-{dropped}.{extension}",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        trace.export(path).unwrap();
+        let result = read(path);
+        fs::remove_file(path).unwrap();
+
+        let (read_dropped, records) = result.unwrap();
+        assert_eq!(read_dropped, dropped);
+        assert_eq!(fields(&records), fields(&trace.records));
+        assert_eq!(records[3].memory_writes, [(40, 8)]);
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        round_trip("csv", 0);
+    }
+
+    #[test]
+    fn csv_round_trip_with_dropped_records() {
+        round_trip("csv", 12);
+    }
+
+    #[test]
+    fn json_lines_round_trip() {
+        round_trip("jsonl", 0);
+    }
+
+    #[test]
+    fn json_lines_round_trip_with_dropped_records() {
+        round_trip("jsonl", 12);
+    }
+
+    #[test]
+    fn malformed_records_are_reported_with_their_line() {
+        let path = std::env::temp_dir().join(format!("cpu-sim-bad-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut text = Vec::new();
+        traced(0).write_csv(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap() + "1,2,3\n";
+        fs::write(path, &text).unwrap();
+        let result = read(path);
+        fs::remove_file(path).unwrap();
+
+        let error = result.err().unwrap().to_string();
+        assert_eq!(error, format!("{path}:7: Malformed trace record"));
+    }
+}
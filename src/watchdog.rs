@@ -0,0 +1,132 @@
+use crate::cpu::CPU;
+use crate::timer::Timer;
+
+// Everything that decides what the program does next. Statistics, cycle counts and
+// replacement bookkeeping don't, and cache lines only count for what they hold.
+#[derive(PartialEq)]
+struct MachineState {
+    registers: [u16; 4],
+    pc: u16,
+    next_pc: u16, // Differs from pc while instructions are in the pipeline
+    sp: u16,
+    epc: u16,
+    in_interrupt: bool,
+    memory: [u16; 64],
+    instruction_memory: Option<[u16; 64]>,
+    timer: Timer,
+    cache_lines: Vec<(bool, bool, usize, Vec<u16>)>,
+}
+
+impl MachineState {
+    fn capture(cpu: &CPU, next_pc: u16) -> Self {
+        let cache_lines = [&cpu.icache, &cpu.cache]
+            .into_iter()
+            .flatten()
+            .flat_map(|cache| cache.sets.iter().flatten())
+            .map(|line| (line.valid, line.dirty, line.tag, line.data.clone()))
+            .collect();
+
+        Self {
+            registers: cpu.get_all_registers(),
+            pc: cpu.pc,
+            next_pc,
+            sp: cpu.sp,
+            epc: cpu.epc,
+            in_interrupt: cpu.in_interrupt,
+            memory: cpu.memory,
+            instruction_memory: cpu.instruction_memory,
+            timer: cpu.timer.clone(),
+            cache_lines,
+        }
+    }
+}
+
+// NOTE: Runaway detection
+// Checked after every completed instruction. Reports a fault that stopped the CPU,
+// and stops a run that uses up its instruction budget, comes back to a machine state
+// it was in before (so it can never halt), or halts on zeroed memory past the end of
+// the program. Loops are found with Brent's
+// algorithm: the state is saved at power-of-two distances and compared against, so
+// a loop is found within twice its length without keeping a history.
+#[derive(Default)]
+pub struct Watchdog {
+    pub budget: Option<u64>, // Instructions allowed per run
+    pub program_end: usize,  // Words loaded from the program, 0 when unknown
+    executed: u64,
+    checkpoint: Option<MachineState>,
+    checkpoint_at: u64, // Instructions executed when the checkpoint was saved
+    distance: u64,      // Instructions until the next checkpoint
+}
+
+impl Watchdog {
+    pub fn new(budget: Option<u64>, program_end: usize) -> Self {
+        Self {
+            budget,
+            program_end,
+            ..Default::default()
+        }
+    }
+
+    // Start counting again, e.g. when a paused run is resumed or the state was edited
+    pub fn restart(&mut self) {
+        self.executed = 0;
+        self.checkpoint = None;
+        self.checkpoint_at = 0;
+        self.distance = 0;
+    }
+
+    // address is the instruction that just completed, next_pc the next one to complete.
+    // A reason to stop restarts the watchdog so the run can be resumed.
+    pub fn check(&mut self, cpu: &CPU, address: u16, next_pc: u16) -> Option<String> {
+        self.executed += 1;
+        let reason = self.reason(cpu, address, next_pc);
+        if reason.is_some() {
+            self.restart();
+        }
+        reason
+    }
+
+    fn reason(&mut self, cpu: &CPU, address: u16, next_pc: u16) -> Option<String> {
+        if let Some(fault) = &cpu.fault {
+            return Some(fault.clone());
+        }
+        if cpu.halted {
+            return (self.program_end > 0 && address as usize >= self.program_end).then(|| {
+                format!(
+                    "Ran off the end of the program: halted on empty memory at {address}, \
+                     the program ends at {}",
+                    self.program_end - 1
+                )
+            });
+        }
+
+        if let Some(budget) = self.budget
+            && self.executed >= budget
+        {
+            return Some(format!(
+                "Instruction budget of {budget} used up, stopped at {next_pc}"
+            ));
+        }
+
+        if let Some(checkpoint) = &self.checkpoint
+            && checkpoint.next_pc == next_pc
+            && *checkpoint == MachineState::capture(cpu, next_pc)
+        {
+            let period = match self.executed - self.checkpoint_at {
+                1 => String::from("every instruction"),
+                length => format!("every {length} instructions"),
+            };
+            return Some(format!(
+                "Infinite loop: the machine state at {next_pc} repeats {period} \
+                 and can never halt"
+            ));
+        }
+
+        if self.executed - self.checkpoint_at >= self.distance {
+            self.checkpoint = Some(MachineState::capture(cpu, next_pc));
+            self.checkpoint_at = self.executed;
+            self.distance = (self.distance * 2).max(1);
+        }
+        None
+    }
+}
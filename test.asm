@@ -1,9 +1,11 @@
+.equ RESULT, 30
+
 LVAL R0, 5
 LVAL R1, 2
 ADD R0, R1
-STORE R0, 30
-LOAD R2, 30
+STORE R0, RESULT
+LOAD R2, RESULT
 SUB R2, R1
-JMP 8
+JMP end
 LVAL R0, 100
-HALT
+end: HALT